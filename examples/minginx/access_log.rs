@@ -0,0 +1,327 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use hyper::{header, Request};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{
+    config::{AccessLogConfig, LogFormat},
+    copy::Transferred,
+};
+
+/// Time of the Common Log Format, e.g. `10/Oct/2000:13:55:36 -0700`.
+const CLF_TIME: &str = "%d/%b/%Y:%H:%M:%S %z";
+
+/// A connection of a `tcp` listener or a request of an `http` listener.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub client: SocketAddr,
+    pub listener: SocketAddr,
+    pub upstream: Option<String>,
+    pub request: Option<RequestLine>,
+    pub status: Option<u16>,
+    /// Bytes of the connection, or of the request and response bodies.
+    pub transferred: Transferred,
+    time: DateTime<Local>,
+    start: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestLine {
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    pub fn new(client: SocketAddr, listener: SocketAddr) -> Self {
+        Self {
+            client,
+            listener,
+            upstream: None,
+            request: None,
+            status: None,
+            transferred: Transferred::default(),
+            time: Local::now(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn http<B>(client: SocketAddr, listener: SocketAddr, req: &Request<B>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        let request = RequestLine {
+            method: req.method().to_string(),
+            target: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+        };
+        Self {
+            request: Some(request),
+            ..Self::new(client, listener)
+        }
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        let duration = self.start.elapsed().as_secs_f64();
+        let upstream = self.upstream.as_deref().unwrap_or("-");
+        let request = self.request.as_ref();
+        let referer = request.and_then(|r| r.referer.as_deref());
+        let user_agent = request.and_then(|r| r.user_agent.as_deref());
+        let Transferred {
+            client_to_upstream: received,
+            upstream_to_client: sent,
+        } = self.transferred;
+        if format == LogFormat::Json {
+            return json!({
+                "time": self.time.to_rfc3339(),
+                "client": self.client,
+                "listener": self.listener,
+                "upstream": self.upstream,
+                "method": request.map(|r| &r.method),
+                "uri": request.map(|r| &r.target),
+                "version": request.map(|r| &r.version),
+                "status": self.status,
+                "referer": referer,
+                "user_agent": user_agent,
+                "bytes_received": received,
+                "bytes_sent": sent,
+                "duration": duration,
+            })
+            .to_string();
+        }
+
+        // 标准字段之后追加 upstream、收到的字节数和耗时
+        let request_line = request.map_or("-".to_owned(), |r| {
+            format!("{} {} {}", r.method, r.target, r.version)
+        });
+        let status = self.status.map_or("-".to_owned(), |s| s.to_string());
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {status} {sent}",
+            self.client.ip(),
+            self.time.format(CLF_TIME),
+            escape(&request_line),
+        );
+        if format == LogFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                escape(referer.unwrap_or("-")),
+                escape(user_agent.unwrap_or("-"))
+            ));
+        }
+        line.push_str(&format!(" {upstream} {received} {duration:.3}"));
+        line
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The access log file, renamed to `<path>.1` when it reaches `max_size`, the
+/// older ones to `<path>.2` and so on up to `keep` files. Lines are written by
+/// a thread of its own, which stops when the log is dropped.
+#[derive(Debug)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    lines: Sender<String>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<Self> {
+        let mut writer = Writer::open(config)
+            .with_context(|| format!("can't open access log {}", config.path.display()))?;
+        let (lines, rx) = mpsc::channel::<String>();
+        // 文件读写会阻塞，不放在 tokio 的线程上
+        thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = writer.append(&line) {
+                        warn!(
+                            "Write access log {} fail with error {e}",
+                            writer.config.path.display()
+                        );
+                    }
+                }
+            })?;
+        Ok(Self {
+            config: config.clone(),
+            lines,
+        })
+    }
+
+    pub fn config(&self) -> &AccessLogConfig {
+        &self.config
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let _ = self.lines.send(entry.format(self.config.format));
+    }
+}
+
+#[derive(Debug)]
+struct Writer {
+    config: AccessLogConfig,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+}
+
+impl Writer {
+    fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        let (file, size) = open_append(&config.path)?;
+        Ok(Self {
+            config: config.clone(),
+            max_bytes: config.max_size.saturating_mul(1024 * 1024),
+            file,
+            size,
+        })
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        // max_size = 0 不轮转
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+            (self.file, self.size) = open_append(&self.config.path)?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            return fs::remove_file(path);
+        }
+        // 最老的一个被覆盖
+        for i in (1..self.config.keep).rev() {
+            let from = rotated(path, i);
+            if from.exists() {
+                fs::rename(from, rotated(path, i + 1))?;
+            }
+        }
+        fs::rename(path, rotated(path, 1))
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{i}"));
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Version;
+
+    fn entry() -> Entry {
+        let req = Request::get("/a?b=1")
+            .version(Version::HTTP_11)
+            .header(header::USER_AGENT, "curl/8.0 \"x\"")
+            .body(())
+            .unwrap();
+        let mut entry = Entry::http(
+            "10.0.0.1:5000".parse().unwrap(),
+            "0.0.0.0:8080".parse().unwrap(),
+            &req,
+        );
+        entry.upstream = Some("127.0.0.1:8081".into());
+        entry.status = Some(200);
+        entry.transferred = Transferred {
+            client_to_upstream: 12,
+            upstream_to_client: 345,
+        };
+        entry
+    }
+
+    #[test]
+    fn formats() {
+        let entry = entry();
+        let time = entry.time.format(CLF_TIME).to_string();
+
+        let common = entry.format(LogFormat::Common);
+        let prefix =
+            format!("10.0.0.1 - - [{time}] \"GET /a?b=1 HTTP/1.1\" 200 345 127.0.0.1:8081 12 ");
+        assert!(common.starts_with(&prefix), "{common}");
+
+        let combined = entry.format(LogFormat::Combined);
+        let prefix = format!(
+            "10.0.0.1 - - [{time}] \"GET /a?b=1 HTTP/1.1\" 200 345 \"-\" \"curl/8.0 \\\"x\\\"\" 127.0.0.1:8081 12 "
+        );
+        assert!(combined.starts_with(&prefix), "{combined}");
+
+        let tcp = Entry::new(entry.client, entry.listener).format(LogFormat::Common);
+        assert!(tcp.contains("] \"-\" - 0 - 0 "), "{tcp}");
+
+        let json: serde_json::Value = serde_json::from_str(&entry.format(LogFormat::Json)).unwrap();
+        assert_eq!(json["client"], "10.0.0.1:5000");
+        assert_eq!(json["upstream"], "127.0.0.1:8081");
+        assert_eq!(json["uri"], "/a?b=1");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes_received"], 12);
+        assert_eq!(json["bytes_sent"], 345);
+    }
+
+    #[test]
+    fn rotates() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("minginx-log-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("access.log");
+        let config = AccessLogConfig {
+            path: path.clone(),
+            format: LogFormat::Json,
+            max_size: 1,
+            keep: 2,
+        };
+        let mut writer = Writer::open(&config)?;
+        // 每个文件放两行，耗时的长度会变
+        let line_len = entry().format(LogFormat::Json).len() as u64 + 1;
+        writer.max_bytes = line_len * 2 + line_len / 2;
+        for _ in 0..7 {
+            writer.append(&entry().format(LogFormat::Json))?;
+        }
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated(&path, 1)), 2);
+        assert_eq!(lines(&rotated(&path, 2)), 2);
+        assert!(!rotated(&path, 3).exists());
+
+        // 很大的 max_size 不会溢出
+        let huge = AccessLogConfig {
+            max_size: u64::MAX,
+            ..config
+        };
+        assert_eq!(Writer::open(&huge)?.max_bytes, u64::MAX);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+const VIRTUAL_NODES: usize = 160; // 每个 upstream 在哈希环上的虚拟节点数
+const FALL: usize = 3;
+const RISE: usize = 2;
+
+/// How an upstream is picked for a new client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, EnumString, Display, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Each upstream in turn.
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest open connections.
+    LeastConn,
+    /// Consistent hash of the client ip, a client sticks to one upstream and
+    /// adding or removing an upstream only moves the clients of that upstream.
+    IpHash,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: String,
+    active: AtomicUsize,
+    healthy: AtomicBool,
+    failures: AtomicUsize,  // 连续失败次数
+    successes: AtomicUsize, // 连续成功次数
+}
+
+impl Upstream {
+    fn new(addr: String) -> Self {
+        Self {
+            addr,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            failures: AtomicUsize::new(0),
+            successes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connections currently proxied to this upstream.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+}
+
+/// An upstream picked for one client, counted as active until dropped.
+#[derive(Debug)]
+pub struct Lease(Arc<Upstream>);
+
+impl Lease {
+    fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        Self(upstream)
+    }
+}
+
+impl Deref for Lease {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Balancer {
+    strategy: Strategy,
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>, // (hash, upstream index)，按 hash 排序
+    fall: usize,
+    rise: usize,
+}
+
+impl Balancer {
+    pub fn new(addrs: impl IntoIterator<Item = String>, strategy: Strategy) -> Self {
+        let upstreams: Vec<_> = addrs
+            .into_iter()
+            .map(|addr| Arc::new(Upstream::new(addr)))
+            .collect();
+        let mut ring: Vec<_> = upstreams
+            .iter()
+            .enumerate()
+            .flat_map(|(i, upstream)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash(&(&upstream.addr, node)), i))
+            })
+            .collect();
+        ring.sort_unstable();
+        Self {
+            strategy,
+            upstreams,
+            next: AtomicUsize::new(0),
+            ring,
+            fall: FALL,
+            rise: RISE,
+        }
+    }
+
+    /// Eject an upstream after `fall` failures in a row, re-admit it after
+    /// `rise` successes in a row.
+    pub fn thresholds(mut self, fall: usize, rise: usize) -> Self {
+        self.fall = fall.max(1);
+        self.rise = rise.max(1);
+        self
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn healthy(&self) -> usize {
+        self.upstreams.iter().filter(|u| u.is_healthy()).count()
+    }
+
+    /// Record the result of a probe or a connect to `upstream`, return the new
+    /// health if it changed.
+    pub fn report(&self, upstream: &Upstream, ok: bool) -> Option<bool> {
+        if ok {
+            upstream.failures.store(0, Ordering::Relaxed);
+            let successes = upstream.successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if successes >= self.rise && !upstream.healthy.swap(true, Ordering::Relaxed) {
+                return Some(true);
+            }
+        } else {
+            upstream.successes.store(0, Ordering::Relaxed);
+            let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= self.fall && upstream.healthy.swap(false, Ordering::Relaxed) {
+                return Some(false);
+            }
+        }
+        None
+    }
+
+    /// Pick a healthy upstream for a client from `ip`, `None` if there is none.
+    pub fn pick(&self, ip: IpAddr) -> Option<Lease> {
+        let len = self.upstreams.len();
+        let healthy = |&i: &usize| self.upstreams[i].is_healthy();
+        let i = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len).map(|n| (start + n) % len).find(healthy)?
+            }
+            Strategy::LeastConn => {
+                // 连接数相同时轮流选择，避免总是压在第一个上
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len)
+                    .map(|n| (start + n) % len)
+                    .filter(healthy)
+                    .min_by_key(|&i| self.upstreams[i].active())?
+            }
+            Strategy::IpHash => {
+                // 沿着环找下一个健康的节点，只有被摘除节点上的客户端会移动
+                let h = hash(&ip);
+                let pos = self.ring.partition_point(|&(node, _)| node < h);
+                (0..self.ring.len())
+                    .map(|n| self.ring[(pos + n) % self.ring.len()].1)
+                    .find(healthy)?
+            }
+        };
+        Some(Lease::new(self.upstreams[i].clone()))
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("127.0.0.1:808{i}")).collect()
+    }
+
+    fn ip(i: u32) -> IpAddr {
+        IpAddr::from((10 << 24 | i).to_be_bytes())
+    }
+
+    #[test]
+    fn round_robin_works() {
+        let balancer = Balancer::new(addrs(3), Strategy::RoundRobin);
+        let picked: Vec<_> = (0..6)
+            .map(|_| balancer.pick(ip(1)).unwrap().addr.clone())
+            .collect();
+        assert_eq!(picked[..3], addrs(3)[..]);
+        assert_eq!(picked[3..], addrs(3)[..]);
+        assert!(Balancer::new(vec![], Strategy::RoundRobin)
+            .pick(ip(1))
+            .is_none());
+    }
+
+    #[test]
+    fn least_conn_works() {
+        let balancer = Balancer::new(addrs(3), Strategy::LeastConn);
+        let a = balancer.pick(ip(1)).unwrap();
+        let b = balancer.pick(ip(1)).unwrap();
+        let c = balancer.pick(ip(1)).unwrap();
+        assert!(a.addr != b.addr && b.addr != c.addr && a.addr != c.addr);
+
+        // b 的连接关闭后，新连接都去 b
+        let b_addr = b.addr.clone();
+        drop(b);
+        let d = balancer.pick(ip(1)).unwrap();
+        assert_eq!(d.addr, b_addr);
+        assert!(balancer.upstreams().iter().all(|u| u.active() == 1));
+    }
+
+    #[test]
+    fn ip_hash_is_consistent() {
+        let three = Balancer::new(addrs(3), Strategy::IpHash);
+        let four = Balancer::new(addrs(4), Strategy::IpHash);
+
+        let mut counts = [0; 4];
+        for i in 0..1000 {
+            let before = three.pick(ip(i)).unwrap().addr.clone();
+            assert_eq!(three.pick(ip(i)).unwrap().addr, before);
+            // 加入第 4 个 upstream 后，客户端要么不动，要么移到新的 upstream
+            let after = four.pick(ip(i)).unwrap().addr.clone();
+            assert!(after == before || after == addrs(4)[3]);
+            counts[addrs(4).iter().position(|a| *a == after).unwrap()] += 1;
+        }
+        // 分布大致均匀
+        assert!(counts.iter().all(|&n| n > 150), "{counts:?}");
+    }
+
+    #[test]
+    fn eject_and_readmit() {
+        let balancer = Balancer::new(addrs(2), Strategy::RoundRobin).thresholds(3, 2);
+        let bad = balancer.upstreams()[0].clone();
+
+        assert_eq!(balancer.report(&bad, false), None);
+        // 成功一次会清零连续失败次数
+        assert_eq!(balancer.report(&bad, true), None);
+        assert_eq!(balancer.report(&bad, false), None);
+        assert_eq!(balancer.report(&bad, false), None);
+        assert_eq!(balancer.report(&bad, false), Some(false));
+        assert_eq!(balancer.report(&bad, false), None);
+        assert_eq!(balancer.healthy(), 1);
+        for _ in 0..4 {
+            assert_eq!(balancer.pick(ip(1)).unwrap().addr, addrs(2)[1]);
+        }
+
+        assert_eq!(balancer.report(&bad, true), None);
+        assert_eq!(balancer.report(&bad, true), Some(true));
+        assert_eq!(balancer.healthy(), 2);
+        let picked: Vec<_> = (0..2)
+            .map(|_| balancer.pick(ip(1)).unwrap().addr.clone())
+            .collect();
+        assert!(picked.contains(&addrs(2)[0]));
+
+        let other = balancer.upstreams()[1].clone();
+        for upstream in [&bad, &other] {
+            for _ in 0..3 {
+                balancer.report(upstream, false);
+            }
+        }
+        assert!(balancer.pick(ip(1)).is_none());
+    }
+
+    #[test]
+    fn ip_hash_skips_ejected() {
+        let balancer = Balancer::new(addrs(3), Strategy::IpHash).thresholds(1, 1);
+        let before: Vec<_> = (0..300)
+            .map(|i| balancer.pick(ip(i)).unwrap().addr.clone())
+            .collect();
+        balancer.report(&balancer.upstreams()[2], false);
+        for (i, before) in before.iter().enumerate() {
+            let after = balancer.pick(ip(i as u32)).unwrap().addr.clone();
+            assert_ne!(after, addrs(3)[2]);
+            // 其他节点上的客户端不受影响
+            assert!(*before == addrs(3)[2] || after == *before);
+        }
+    }
+}
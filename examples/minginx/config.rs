@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
+use serde::Deserialize;
+use strum::Display;
+
+use crate::{
+    balance::Strategy,
+    health::{HealthChecker, Probe},
+};
+
+/// Listeners, upstream pools and the rules routing clients to the pools.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub access_log: Option<AccessLogConfig>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    pub listeners: Vec<ListenerConfig>,
+    pub pools: HashMap<String, PoolConfig>,
+}
+
+/// Log a line per connection of `tcp` listeners and per request of `http` listeners.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Megabytes to rotate the file at, 0 to never rotate.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Rotated files kept as `<path>.1`, `<path>.2`...
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_max_size() -> u64 {
+    100
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// Clients accepted by all the listeners, by the address of the client, the one
+/// in the PROXY header on `proxy_protocol` listeners.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Concurrent connections.
+    pub max_connections: Option<usize>,
+    /// Concurrent connections from the same address.
+    pub max_connections_per_ip: Option<usize>,
+    /// New connections per second.
+    pub accept_rate: Option<u32>,
+    /// New connections per second from the same address.
+    pub accept_rate_per_ip: Option<u32>,
+    /// Networks accepted, all when empty.
+    pub allow: Vec<IpNet>,
+    /// Networks refused, even when in `allow`.
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Common Log Format followed by the upstream, the bytes received and the
+    /// seconds taken.
+    Common,
+    /// `common` with the referer and user agent, like nginx's `combined`.
+    #[default]
+    Combined,
+    /// A JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: SocketAddr,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Pool of the clients no route matches.
+    pub pool: String,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Terminate TLS, `tls = {}` uses `examples/cert.pem` and `examples/key.pem`.
+    pub tls: Option<TlsConfig>,
+    /// Seconds a `tcp` connection may stay without traffic in both directions.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Expect a v1 or v2 PROXY protocol header telling the real client.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain of the clients without a matching `sni` entry.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Certificates picked by the server name the client asks for.
+    pub sni: Vec<SniCert>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCert {
+    pub server_name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: "examples/cert.pem".into(),
+            key: "examples/key.pem".into(),
+            sni: vec![],
+        }
+    }
+}
+
+/// How a listener proxies its clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Display, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Splice the bytes of each connection to one upstream.
+    #[default]
+    Tcp,
+    /// Parse the HTTP/1.1 and HTTP/2 requests and route each of them.
+    Http,
+}
+
+/// Send the clients matching the rule to `pool`, the first matching route wins.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Client network, e.g. `10.0.0.0/8`.
+    pub source: Option<IpNet>,
+    /// `Host` of the request without the port, `http` listeners only.
+    pub host: Option<String>,
+    /// Beginning of the request path, e.g. `/api/`, `http` listeners only.
+    pub path_prefix: Option<String>,
+    pub pool: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Protocol spoken to the upstreams by `http` listeners.
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Idle connections kept by `http` listeners.
+    #[serde(default)]
+    pub keepalive: KeepAliveConfig,
+    /// Send a PROXY protocol header to the upstreams.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Connect to the upstreams over TLS.
+    pub tls: Option<UpstreamTls>,
+}
+
+/// Version of the PROXY protocol header sent to the upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Text header, `PROXY TCP4 <source> <destination> <ports>`.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTls {
+    /// CA certificates the upstreams are verified with, the webpki roots by default.
+    pub ca: Option<PathBuf>,
+    /// Name sent and verified, the host of the upstream address by default.
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Http1,
+    /// HTTP/2 without TLS (h2c), the upstream must support prior knowledge.
+    Http2,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// `tcp`, `http` or `http:/path`.
+    pub check: Probe,
+    /// Seconds between two probes.
+    pub interval: u64,
+    /// Seconds to wait for a probe or a connect.
+    pub timeout: u64,
+    /// Failures in a row to eject an upstream.
+    pub fall: usize,
+    /// Successes in a row to re-admit it.
+    pub rise: usize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            check: Probe::Tcp,
+            interval: 5,
+            timeout: 2,
+            fall: 3,
+            rise: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAliveConfig {
+    /// Idle connections kept per upstream, 0 to open one for each request.
+    pub size: usize,
+    /// Seconds an idle connection is kept.
+    pub max_idle: u64,
+    /// Seconds a connection is reused at most.
+    pub max_lifetime: u64,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            size: 16,
+            max_idle: 60,
+            max_lifetime: 600,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("can't read config file {}", path.display()))?;
+        let config: Config = toml::from_str(&s)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(anyhow!("at least one listener is required"));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if self.listeners[..i].iter().any(|l| l.bind == listener.bind) {
+                return Err(anyhow!("listener {} is defined twice", listener.bind));
+            }
+            let l7 = listener
+                .routes
+                .iter()
+                .any(|r| r.host.is_some() || r.path_prefix.is_some());
+            if l7 && listener.mode != Mode::Http {
+                return Err(anyhow!(
+                    "listener {} routes by host or path_prefix, which needs mode = \"http\"",
+                    listener.bind
+                ));
+            }
+            if listener.idle_timeout == 0 {
+                return Err(anyhow!(
+                    "idle_timeout of listener {} must be at least 1 second",
+                    listener.bind
+                ));
+            }
+            let pools =
+                std::iter::once(&listener.pool).chain(listener.routes.iter().map(|r| &r.pool));
+            for pool in pools {
+                if !self.pools.contains_key(pool) {
+                    return Err(anyhow!(
+                        "listener {} uses unknown pool {pool}",
+                        listener.bind
+                    ));
+                }
+            }
+        }
+        let limits = &self.limits;
+        if limits.max_connections == Some(0)
+            || limits.max_connections_per_ip == Some(0)
+            || limits.accept_rate == Some(0)
+            || limits.accept_rate_per_ip == Some(0)
+        {
+            return Err(anyhow!(
+                "limits must be at least 1, leave them out for no limit"
+            ));
+        }
+        for (name, pool) in &self.pools {
+            if pool.upstreams.is_empty() {
+                return Err(anyhow!("pool {name} must list at least one upstream"));
+            }
+            if pool.health.interval == 0 || pool.health.timeout == 0 {
+                return Err(anyhow!(
+                    "health interval and timeout of pool {name} must be at least 1 second"
+                ));
+            }
+            if pool.keepalive.max_idle == 0 || pool.keepalive.max_lifetime == 0 {
+                return Err(anyhow!(
+                    "keepalive max_idle and max_lifetime of pool {name} must be at least 1 second"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn listener(&self, bind: SocketAddr) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|l| l.bind == bind)
+    }
+}
+
+impl ListenerConfig {
+    /// Name of the pool serving a client from `ip`, `host` and `path` are the
+    /// ones of the request in `http` mode.
+    pub fn route(&self, ip: IpAddr, host: Option<&str>, path: Option<&str>) -> &str {
+        self.routes
+            .iter()
+            .find(|route| route.matches(ip, host, path))
+            .map_or(&self.pool, |route| &route.pool)
+    }
+}
+
+impl LimitsConfig {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+impl Route {
+    fn matches(&self, ip: IpAddr, host: Option<&str>, path: Option<&str>) -> bool {
+        self.source.is_none_or(|net| net.contains(&ip))
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|expected| host.is_some_and(|host| host.eq_ignore_ascii_case(expected)))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path.is_some_and(|path| path.starts_with(prefix.as_str())))
+    }
+}
+
+impl HealthConfig {
+    pub fn checker(&self) -> HealthChecker {
+        HealthChecker {
+            probe: self.check.clone(),
+            interval: Duration::from_secs(self.interval),
+            timeout: Duration::from_secs(self.timeout),
+            tls: None,
+            proxy_protocol: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_is_valid() -> Result<()> {
+        let config = Config::from_file("examples/minginx/minginx.toml")?;
+        let listener = &config.listeners[0];
+        assert_eq!(listener.bind, "0.0.0.0:8080".parse()?);
+        assert_eq!(config.pools[&listener.pool].upstreams, ["127.0.0.1:8081"]);
+        Ok(())
+    }
+
+    #[test]
+    fn routes_by_source() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [[listeners]]
+            bind = "127.0.0.1:8080"
+            pool = "web"
+            routes = [{ source = "10.0.0.0/8", pool = "internal" }]
+
+            [pools.web]
+            upstreams = ["127.0.0.1:8081", "127.0.0.1:8082"]
+            strategy = "least_conn"
+
+            [pools.internal]
+            upstreams = ["127.0.0.1:8083"]
+            health = { check = "http:/healthz", fall = 1 }
+            "#,
+        )?;
+        config.validate()?;
+        let listener = config.listener("127.0.0.1:8080".parse()?).unwrap();
+        assert_eq!(listener.route("10.1.2.3".parse()?, None, None), "internal");
+        assert_eq!(listener.route("192.168.1.1".parse()?, None, None), "web");
+        assert_eq!(config.pools["web"].strategy, Strategy::LeastConn);
+        let health = &config.pools["internal"].health;
+        assert_eq!(health.check, Probe::Http("/healthz".into()));
+        // 未写的字段取默认值
+        assert_eq!((health.fall, health.rise, health.interval), (1, 2, 5));
+        Ok(())
+    }
+
+    #[test]
+    fn routes_by_host_and_path() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [[listeners]]
+            bind = "127.0.0.1:8080"
+            mode = "http"
+            pool = "web"
+            routes = [
+                { host = "api.example.com", path_prefix = "/v2/", pool = "api_v2" },
+                { host = "api.example.com", pool = "api" },
+                { path_prefix = "/static/", pool = "static" },
+            ]
+
+            [pools.web]
+            upstreams = ["127.0.0.1:8081"]
+            protocol = "http2"
+            [pools.api]
+            upstreams = ["127.0.0.1:8082"]
+            [pools.api_v2]
+            upstreams = ["127.0.0.1:8083"]
+            [pools.static]
+            upstreams = ["127.0.0.1:8084"]
+            "#,
+        )?;
+        config.validate()?;
+        let listener = &config.listeners[0];
+        assert_eq!(listener.mode, Mode::Http);
+        assert_eq!(config.pools["web"].protocol, Protocol::Http2);
+        assert_eq!(config.pools["api"].protocol, Protocol::Http1);
+        let ip = "127.0.0.1".parse()?;
+        let cases = [
+            (Some("api.example.com"), "/v2/users", "api_v2"),
+            (Some("API.example.com"), "/v1/users", "api"),
+            (Some("www.example.com"), "/static/a.css", "static"),
+            (Some("www.example.com"), "/v2/users", "web"),
+            (None, "/index.html", "web"),
+        ];
+        for (host, path, pool) in cases {
+            assert_eq!(
+                listener.route(ip, host, Some(path)),
+                pool,
+                "{host:?} {path}"
+            );
+        }
+        // tcp 模式下没有 host，只能按 source 匹配
+        assert_eq!(listener.route(ip, None, None), "web");
+        Ok(())
+    }
+
+    #[test]
+    fn tls_defaults() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [[listeners]]
+            bind = "127.0.0.1:8443"
+            pool = "web"
+            tls = { sni = [{ server_name = "a.com", cert = "a.pem", key = "a.key" }] }
+
+            [pools.web]
+            upstreams = ["127.0.0.1:8081"]
+            tls = {}
+            "#,
+        )?;
+        let tls = config.listeners[0].tls.as_ref().unwrap();
+        assert_eq!(tls.cert, PathBuf::from("examples/cert.pem"));
+        assert_eq!(tls.sni[0].server_name, "a.com");
+        assert_eq!(config.pools["web"].tls, Some(UpstreamTls::default()));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let cases = [
+            ("listeners = []\n[pools]", "at least one listener"),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"none\"\n[pools]",
+                "unknown pool none",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[pools.a]\nupstreams = []",
+                "at least one upstream",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[pools.a]\nupstreams = [\"x:1\"]\nhealth = { interval = 0 }",
+                "at least 1 second",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[pools.a]\nupstreams = [\"x:1\"]",
+                "defined twice",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\nroutes = [{ host = \"a.com\", pool = \"a\" }]\n[pools.a]\nupstreams = [\"x:1\"]",
+                "needs mode = \"http\"",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\nidle_timeout = 0\n[pools.a]\nupstreams = [\"x:1\"]",
+                "idle_timeout of listener",
+            ),
+            (
+                "[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[pools.a]\nupstreams = [\"x:1\"]\nkeepalive = { max_lifetime = 0 }",
+                "keepalive max_idle and max_lifetime",
+            ),
+            (
+                "[limits]\nmax_connections_per_ip = 0\n[[listeners]]\nbind = \"127.0.0.1:1\"\npool = \"a\"\n[pools.a]\nupstreams = [\"x:1\"]",
+                "limits must be at least 1",
+            ),
+        ];
+        for (s, msg) in cases {
+            let config: Config = toml::from_str(s).unwrap();
+            let e = config.validate().unwrap_err().to_string();
+            assert!(e.contains(msg), "{e} should contain {msg}");
+        }
+        assert!(toml::from_str::<Config>("unknown = 1\nlisteners = []\n[pools]").is_err());
+        assert!(toml::from_str::<Config>(
+            "listeners = []\n[pools.a]\nupstreams = [\"x:1\"]\nstrategy = \"random\""
+        )
+        .is_err());
+    }
+}
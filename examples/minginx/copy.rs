@@ -0,0 +1,223 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep_until, Instant},
+};
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Bytes copied in each direction of a proxied connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Transferred {
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+}
+
+/// When bytes last moved in either direction.
+struct Activity {
+    start: Instant,
+    last: AtomicU64, // start 之后的毫秒数
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn deadline(&self, idle: Duration) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed)) + idle
+    }
+}
+
+/// Copy between `client` and `upstream` until both directions are closed,
+/// counting the bytes in `transferred`. EOF on one side shuts down the writing
+/// half of the other side, so the opposite direction keeps going. Fails with
+/// `TimedOut` when no byte moves for `idle`.
+pub async fn copy_bidirectional<C, U>(
+    client: C,
+    upstream: U,
+    idle: Duration,
+    transferred: &mut Transferred,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = io::split(client);
+    let (mut upstream_read, mut upstream_write) = io::split(upstream);
+    let activity = Activity::new();
+
+    let to_upstream = copy_half(
+        &mut client_read,
+        &mut upstream_write,
+        &mut transferred.client_to_upstream,
+        &activity,
+    );
+    let to_client = copy_half(
+        &mut upstream_read,
+        &mut client_write,
+        &mut transferred.upstream_to_client,
+        &activity,
+    );
+    let watchdog = async {
+        // 两个方向都没有数据时才算空闲
+        loop {
+            let deadline = activity.deadline(idle);
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    };
+
+    tokio::select! {
+        result = async { tokio::try_join!(to_upstream, to_client) } => result.map(|_| ()),
+        _ = watchdog => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("idle for {idle:?}"),
+        )),
+    }
+}
+
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &mut u64,
+    activity: &Activity,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // 这一端不再发送，告诉另一端，反方向继续复制
+            return match writer.shutdown().await {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+                _ => Ok(()),
+            };
+        }
+        writer.write_all(&buf[..n]).await?;
+        // TLS 流会缓存写入的数据
+        writer.flush().await?;
+        *copied += n as u64;
+        activity.touch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::echo_server;
+    use tokio::{
+        io::duplex,
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn echo_through_proxy() -> anyhow::Result<()> {
+        let upstream_addr = echo_server("", None).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let proxy = tokio::spawn(async move {
+            let (client, _) = listener.accept().await?;
+            let upstream = TcpStream::connect(upstream_addr).await?;
+            let mut transferred = Transferred::default();
+            copy_bidirectional(client, upstream, Duration::from_secs(5), &mut transferred).await?;
+            Ok::<_, io::Error>(transferred)
+        });
+
+        let mut client = TcpStream::connect(addr).await?;
+        for msg in [&b"Hello, server!"[..], b"again"] {
+            client.write_all(msg).await?;
+            let mut buf = vec![0u8; msg.len()];
+            client.read_exact(&mut buf).await?;
+            assert_eq!(buf, msg);
+        }
+        // 客户端关闭写，echo 服务收到 EOF 后关闭，客户端最终读到 EOF
+        client.shutdown().await?;
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty());
+
+        let transferred = proxy.await??;
+        assert_eq!(
+            transferred,
+            Transferred {
+                client_to_upstream: 19,
+                upstream_to_client: 19,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_other_direction() -> anyhow::Result<()> {
+        let (client, mut client_peer) = duplex(64);
+        let (upstream, mut upstream_peer) = duplex(64);
+        let proxy = tokio::spawn(async move {
+            let mut transferred = Transferred::default();
+            copy_bidirectional(client, upstream, Duration::from_secs(5), &mut transferred)
+                .await
+                .map(|_| transferred)
+        });
+
+        // upstream 读到 EOF 之后才回复，例如一次性的请求
+        let upstream_task = tokio::spawn(async move {
+            let mut request = vec![];
+            upstream_peer.read_to_end(&mut request).await?;
+            upstream_peer.write_all(b"got ").await?;
+            upstream_peer.write_all(&request).await?;
+            upstream_peer.shutdown().await
+        });
+
+        client_peer.write_all(b"request").await?;
+        client_peer.shutdown().await?;
+        let mut response = vec![];
+        client_peer.read_to_end(&mut response).await?;
+        assert_eq!(response, b"got request");
+        upstream_task.await??;
+
+        let transferred = proxy.await??;
+        assert_eq!(transferred.client_to_upstream, 7);
+        assert_eq!(transferred.upstream_to_client, 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() -> anyhow::Result<()> {
+        let (client, mut client_peer) = duplex(64);
+        let (upstream, _upstream_peer) = duplex(64);
+        client_peer.write_all(b"ping").await?;
+
+        let mut transferred = Transferred::default();
+        let started = Instant::now();
+        let e = copy_bidirectional(
+            client,
+            upstream,
+            Duration::from_millis(100),
+            &mut transferred,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(transferred.client_to_upstream, 4);
+        assert_eq!(transferred.upstream_to_client, 0);
+        Ok(())
+    }
+}
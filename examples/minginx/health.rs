@@ -0,0 +1,285 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    balance::{Balancer, Upstream},
+    config::ProxyProtocol,
+    proxy_protocol::local_header,
+    tls::{BoxStream, UpstreamConnector},
+};
+
+/// Sent to the client when there is no healthy upstream.
+pub const FALLBACK_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: 20\r\n\
+Connection: close\r\n\
+\r\n\
+no healthy upstream\n";
+
+/// How an upstream is probed: `tcp` only connects, `http:/healthz` also expects
+/// a 2xx or 3xx answer to `GET /healthz`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Probe {
+    Tcp,
+    Http(String),
+}
+
+impl TryFrom<String> for Probe {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl FromStr for Probe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "tcp" => Ok(Probe::Tcp),
+            None if s == "http" => Ok(Probe::Http("/".to_owned())),
+            Some(("http", path)) if path.starts_with('/') => Ok(Probe::Http(path.to_owned())),
+            _ => Err(anyhow!(
+                "Unknown health check {s}, expect tcp, http or http:/path"
+            )),
+        }
+    }
+}
+
+impl Probe {
+    /// Probe `addr` once, an error if it isn't healthy. With `tls` the handshake
+    /// is part of the probe, with `proxy_protocol` a `LOCAL` header is sent first.
+    pub async fn check(
+        &self,
+        addr: &str,
+        limit: Duration,
+        tls: Option<&UpstreamConnector>,
+        proxy_protocol: Option<ProxyProtocol>,
+    ) -> Result<()> {
+        timeout(limit, self.check_inner(addr, tls, proxy_protocol))
+            .await
+            .map_err(|_| anyhow!("timeout after {limit:?}"))?
+    }
+
+    async fn check_inner(
+        &self,
+        addr: &str,
+        tls: Option<&UpstreamConnector>,
+        proxy_protocol: Option<ProxyProtocol>,
+    ) -> Result<()> {
+        let mut stream = TcpStream::connect(addr).await?;
+        if let Some(version) = proxy_protocol {
+            stream.write_all(&local_header(version)).await?;
+        }
+        let mut stream: BoxStream = match tls {
+            Some(tls) => tls.connect(addr, stream).await?,
+            None => Box::new(stream),
+        };
+        let Probe::Http(path) = self else {
+            return Ok(());
+        };
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // 只需要状态行，例如 HTTP/1.1 200 OK
+        let mut buf = [0u8; 32];
+        let mut n = 0;
+        while n < buf.len() {
+            match stream.read(&mut buf[n..]).await? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        let status_line = String::from_utf8_lossy(&buf[..n]);
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("invalid response {status_line:?}"))?;
+        if (200..400).contains(&status) {
+            Ok(())
+        } else {
+            Err(anyhow!("status {status}"))
+        }
+    }
+}
+
+/// Periodically probes every upstream, ejecting and re-admitting them through
+/// the balancer.
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub tls: Option<UpstreamConnector>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+}
+
+impl HealthChecker {
+    pub async fn run_once(&self, balancer: &Balancer) {
+        let checks = balancer.upstreams().iter().map(|upstream| async move {
+            let result = self
+                .probe
+                .check(
+                    &upstream.addr,
+                    self.timeout,
+                    self.tls.as_ref(),
+                    self.proxy_protocol,
+                )
+                .await;
+            report(balancer, upstream, result);
+        });
+        join_all(checks).await;
+    }
+
+    pub async fn run(self, balancer: Arc<Balancer>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            self.run_once(&balancer).await;
+        }
+    }
+}
+
+/// Record a probe or connect result, logging when the upstream changes state.
+/// Each failure alone is only logged at debug level.
+pub fn report<T, E: std::fmt::Display>(
+    balancer: &Balancer,
+    upstream: &Upstream,
+    result: Result<T, E>,
+) {
+    let error = result.err().map(|e| e.to_string());
+    if let Some(e) = &error {
+        debug!("Upstream {} check fail with error {e}", upstream.addr);
+    }
+    match balancer.report(upstream, error.is_none()) {
+        Some(true) => info!("Upstream {} is healthy again", upstream.addr),
+        Some(false) => {
+            warn!(
+                "Upstream {} is ejected, last error {}",
+                upstream.addr,
+                error.unwrap_or_default()
+            );
+            if balancer.healthy() == 0 {
+                warn!("No healthy upstream left");
+            }
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::Strategy;
+    use tokio::net::TcpListener;
+
+    /// Answers every connection with `status`.
+    async fn http_server(status: u16) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let res = format!("HTTP/1.1 {status} X\r\nContent-Length: 0\r\n\r\n");
+                let _ = stream.write_all(res.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    async fn closed_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn parse_probe() {
+        assert_eq!("tcp".parse::<Probe>().unwrap(), Probe::Tcp);
+        assert_eq!("http".parse::<Probe>().unwrap(), Probe::Http("/".into()));
+        assert_eq!(
+            "http:/healthz".parse::<Probe>().unwrap(),
+            Probe::Http("/healthz".into())
+        );
+        assert!("udp".parse::<Probe>().is_err());
+        assert!("http:healthz".parse::<Probe>().is_err());
+    }
+
+    #[tokio::test]
+    async fn probes_work() {
+        let limit = Duration::from_millis(500);
+        let ok = http_server(200).await;
+        let failing = http_server(500).await;
+        let closed = closed_addr().await;
+
+        assert!(Probe::Tcp.check(&ok, limit, None, None).await.is_ok());
+        assert!(Probe::Tcp.check(&failing, limit, None, None).await.is_ok());
+        assert!(Probe::Tcp.check(&closed, limit, None, None).await.is_err());
+
+        let http = Probe::Http("/healthz".into());
+        assert!(http.check(&ok, limit, None, None).await.is_ok());
+        assert!(http.check(&failing, limit, None, None).await.is_err());
+        assert!(http.check(&closed, limit, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn probes_send_proxy_header() {
+        // 只接受带 LOCAL 头的连接
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Ok(None) = crate::proxy_protocol::read_header(&mut stream).await {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+                }
+            }
+        });
+
+        let limit = Duration::from_millis(500);
+        let http = Probe::Http("/".into());
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            assert!(http.check(&addr, limit, None, Some(version)).await.is_ok());
+        }
+        assert!(http.check(&addr, limit, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checker_ejects_and_readmits() {
+        let ok = http_server(200).await;
+        let closed = closed_addr().await;
+        let balancer =
+            Balancer::new([ok.clone(), closed.clone()], Strategy::RoundRobin).thresholds(2, 1);
+        let checker = HealthChecker {
+            probe: Probe::Tcp,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_millis(500),
+            tls: None,
+            proxy_protocol: None,
+        };
+
+        checker.run_once(&balancer).await;
+        assert_eq!(balancer.healthy(), 2);
+        checker.run_once(&balancer).await;
+        assert_eq!(balancer.healthy(), 1);
+        assert_eq!(balancer.pick([127, 0, 0, 1].into()).unwrap().addr, ok);
+
+        // 端口重新监听后恢复
+        let _listener = TcpListener::bind(&closed).await.unwrap();
+        checker.run_once(&balancer).await;
+        assert_eq!(balancer.healthy(), 2);
+    }
+}
@@ -0,0 +1,676 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    service::service_fn,
+    Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    access_log::{AccessLog, Entry},
+    config::Protocol,
+    keepalive::Connection,
+    proxy_protocol::Addresses,
+    server::State,
+    tls::BoxStream,
+};
+
+pub type Body = BoxBody<Bytes, hyper::Error>;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Headers which only apply to one connection and are never forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Serve the HTTP/1.1 or HTTP/2 requests of `client`, each routed to a pool of
+/// the listener `bind`.
+pub async fn serve(
+    client: BoxStream,
+    addrs: Addresses,
+    bind: SocketAddr,
+    tls: bool,
+    state: watch::Receiver<Arc<State>>,
+) {
+    let proto = if tls { "https" } else { "http" };
+    let service = service_fn(move |req| handle(req, addrs, bind, proto, state.clone()));
+    // auto 同时支持 HTTP/1.1 keep-alive 和 HTTP/2 (h2c)
+    let builder = auto::Builder::new(TokioExecutor::new());
+    if let Err(e) = builder
+        .serve_connection(TokioIo::new(client), service)
+        .await
+    {
+        warn!("Serve client {} fail with error {e}", addrs.source);
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    addrs: Addresses,
+    bind: SocketAddr,
+    proto: &'static str,
+    state: watch::Receiver<Arc<State>>,
+) -> Result<Response<Body>, Infallible> {
+    let access_log = state.borrow().access_log.clone();
+    let Some(log) = access_log else {
+        let (res, _) = route(req.map(|body| body.boxed()), addrs, bind, proto, state).await;
+        return Ok(res);
+    };
+
+    // 两个方向的 body 边转发边计数，响应发完再写日志
+    let mut entry = Entry::http(addrs.source, bind, &req);
+    let received = Arc::new(AtomicU64::new(0));
+    let counter = received.clone();
+    let req = req.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            frame
+        })
+        .boxed()
+    });
+    let (res, upstream) = route(req, addrs, bind, proto, state).await;
+    entry.upstream = upstream;
+    entry.status = Some(res.status().as_u16());
+    let mut logged = Logged {
+        log,
+        entry,
+        received,
+    };
+    Ok(res.map(|body| {
+        body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                logged.sent(data.len());
+            }
+            frame
+        })
+        .boxed()
+    }))
+}
+
+/// Writes the access log entry of a request when its response is done.
+struct Logged {
+    log: Arc<AccessLog>,
+    entry: Entry,
+    received: Arc<AtomicU64>,
+}
+
+impl Logged {
+    fn sent(&mut self, len: usize) {
+        self.entry.transferred.upstream_to_client += len as u64;
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        self.entry.transferred.client_to_upstream = self.received.load(Ordering::Relaxed);
+        self.log.write(&self.entry);
+    }
+}
+
+/// Proxy `req` to the pool it's routed to, with the upstream if one was chosen.
+async fn route(
+    req: Request<Body>,
+    addrs: Addresses,
+    bind: SocketAddr,
+    proto: &'static str,
+    state: watch::Receiver<Arc<State>>,
+) -> (Response<Body>, Option<String>) {
+    let addr = addrs.source;
+    let host = request_host(&req);
+    let pool = state
+        .borrow()
+        .pool(bind, addr.ip(), host.as_deref(), Some(req.uri().path()))
+        .cloned();
+    let Some(pool) = pool else {
+        warn!("No pool for client {addr} on {bind}");
+        return (text(StatusCode::SERVICE_UNAVAILABLE, "no route\n"), None);
+    };
+    let (lease, mut conn) = match pool.http_connection(addrs).await {
+        Ok(Some(upstream)) => upstream,
+        Ok(None) => {
+            warn!("No healthy upstream for client {addr}");
+            let res = text(StatusCode::SERVICE_UNAVAILABLE, "no healthy upstream\n");
+            return (res, None);
+        }
+        Err(e) => {
+            warn!("Connect upstream for client {addr} fail with error {e}");
+            return (text(StatusCode::BAD_GATEWAY, "bad gateway\n"), None);
+        }
+    };
+    info!(
+        "Proxy {} {} of client {addr} to upstream {}",
+        req.method(),
+        req.uri().path(),
+        lease.addr
+    );
+    let forwarded = Forwarded { addr, host, proto };
+    let (protocol, tls) = (pool.config.protocol, pool.config.tls.is_some());
+    let result = forward(req, forwarded, protocol, tls, &lease.addr, &mut conn).await;
+    // 连接可以发下一个请求时放回池里
+    pool.checkin(&lease.addr, conn);
+    let upstream = Some(lease.addr.clone());
+    match result {
+        // lease 跟着响应走，body 读完才释放，least_conn 按进行中的请求计数
+        Ok(res) => {
+            let res = res.map(|body| {
+                body.map_frame(move |frame| {
+                    let _lease = &lease;
+                    frame
+                })
+                .boxed()
+            });
+            (res, upstream)
+        }
+        Err(e) => {
+            warn!("Proxy request of client {addr} fail with error {e}");
+            (text(StatusCode::BAD_GATEWAY, "bad gateway\n"), upstream)
+        }
+    }
+}
+
+/// Who a request is forwarded for.
+struct Forwarded {
+    addr: SocketAddr,
+    host: Option<String>,
+    proto: &'static str,
+}
+
+async fn forward(
+    mut req: Request<Body>,
+    forwarded: Forwarded,
+    protocol: Protocol,
+    tls: bool,
+    upstream: &str,
+    conn: &mut Connection,
+) -> Result<Response<Incoming>> {
+    // HTTP/2 的请求把 host 放在 uri 里，HTTP/1.1 需要 Host 头
+    if !req.headers().contains_key(header::HOST) {
+        if let Some(authority) = req.uri().authority() {
+            let value = HeaderValue::from_str(authority.as_str())?;
+            req.headers_mut().insert(header::HOST, value);
+        }
+    }
+    remove_hop_by_hop(req.headers_mut());
+    add_forwarded(req.headers_mut(), &forwarded)?;
+
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    match protocol {
+        Protocol::Http1 => {
+            *req.uri_mut() = path.parse::<Uri>()?;
+            *req.version_mut() = Version::HTTP_11;
+        }
+        Protocol::Http2 => {
+            let authority = match req.headers().get(header::HOST) {
+                Some(value) => value.to_str()?.to_owned(),
+                None => upstream.to_owned(),
+            };
+            // 重新加密时 :scheme 是 https
+            let scheme = if tls { "https" } else { "http" };
+            *req.uri_mut() = format!("{scheme}://{authority}{path}").parse::<Uri>()?;
+            *req.version_mut() = Version::HTTP_2;
+        }
+    }
+    let mut res = conn.send_request(req).await?;
+    remove_hop_by_hop(res.headers_mut());
+    Ok(res)
+}
+
+/// `Host` of the request, or the authority of HTTP/2 requests, without the port.
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.headers().get(header::HOST) {
+        Some(value) => value.to_str().ok()?.parse::<Uri>().ok()?.host()?.to_owned(),
+        None => req.uri().host()?.to_owned(),
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    // Connection 里列出的头也只对当前连接有效
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/// Append the client to `X-Forwarded-For` and `Forwarded`, set
+/// `X-Forwarded-Host` and `X-Forwarded-Proto`.
+fn add_forwarded(headers: &mut HeaderMap, forwarded: &Forwarded) -> Result<()> {
+    let ip = forwarded.addr.ip();
+    let xff = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(prev) => format!("{prev}, {ip}"),
+        None => ip.to_string(),
+    };
+    headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(&xff)?);
+
+    // RFC 7239: IPv6 地址需要加方括号和引号
+    let node = if ip.is_ipv6() {
+        format!("\"[{ip}]\"")
+    } else {
+        ip.to_string()
+    };
+    let proto = forwarded.proto;
+    let mut value = format!("for={node};proto={proto}");
+    if let Some(host) = &forwarded.host {
+        value.push_str(&format!(";host=\"{host}\""));
+        headers.insert(X_FORWARDED_HOST, HeaderValue::from_str(host)?);
+    }
+    headers.append(header::FORWARDED, HeaderValue::from_str(&value)?);
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    Ok(())
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let body = Full::new(Bytes::from_static(body.as_bytes()))
+        .map_err(|never| match never {})
+        .boxed();
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, Mode, TlsConfig, UpstreamTls},
+        keepalive::tests::counting_upstream,
+        tls::{self, UpstreamConnector},
+    };
+    use axum::{extract::Request as AxumRequest, routing::any, Router};
+    use std::fs;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Answers `name path host x-forwarded-for forwarded` over HTTP/1.1 and
+    /// HTTP/2.
+    async fn upstream(name: &'static str) -> SocketAddr {
+        let app = Router::new().fallback(any(move |req: AxumRequest| async move {
+            let header = |name| {
+                req.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("-")
+                    .to_owned()
+            };
+            format!(
+                "{name} {} {} {} {}",
+                req.uri().path_and_query().unwrap(),
+                header(header::HOST.as_str()),
+                header(X_FORWARDED_FOR),
+                header(header::FORWARDED.as_str()),
+            )
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// An http listener routing `api.example.com` to `api` and `/static/` to
+    /// `static`, over TLS with the default certificate if `tls`.
+    async fn proxy(tls: bool) -> Result<SocketAddr> {
+        let (web, api, static_) = (
+            upstream("web").await,
+            upstream("api").await,
+            upstream("static").await,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind = listener.local_addr()?;
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [[listeners]]
+            bind = "{bind}"
+            mode = "http"
+            pool = "web"
+            routes = [
+                {{ host = "api.example.com", pool = "api" }},
+                {{ path_prefix = "/static/", pool = "static" }},
+            ]
+            [pools.web]
+            upstreams = ["{web}"]
+            [pools.api]
+            upstreams = ["{api}"]
+            protocol = "http2"
+            [pools.static]
+            upstreams = ["{static_}"]
+            "#
+        ))?;
+        config.validate()?;
+        let (_, state) = watch::channel(Arc::new(State::new(config, None)?));
+        let acceptor = tls::acceptor(&TlsConfig::default(), Mode::Http)?;
+        tokio::spawn(async move {
+            while let Ok((client, addr)) = listener.accept().await {
+                let client: BoxStream = match tls {
+                    true => Box::new(acceptor.accept(client).await.unwrap()),
+                    false => Box::new(client),
+                };
+                let addrs = Addresses {
+                    source: addr,
+                    destination: bind,
+                };
+                tokio::spawn(serve(client, addrs, bind, tls, state.clone()));
+            }
+        });
+        Ok(bind)
+    }
+
+    async fn body(res: Response<Incoming>) -> Result<String> {
+        let bytes = res.into_body().collect().await?.to_bytes();
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    fn get(uri: &str, host: Option<&str>) -> Request<Full<Bytes>> {
+        let mut builder = Request::get(uri).header("x-forwarded-for", "10.0.0.1");
+        if let Some(host) = host {
+            builder = builder.header(header::HOST, host);
+        }
+        builder.body(Full::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http1_keep_alive_and_routing() -> Result<()> {
+        let addr = proxy(false).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+
+        // 同一个连接上的多个请求分别路由
+        let res = sender
+            .send_request(get("/users?page=1", Some("api.example.com:8080")))
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            body(res).await?,
+            "api /users?page=1 api.example.com:8080 10.0.0.1, 127.0.0.1 for=127.0.0.1;proto=http;host=\"api.example.com\""
+        );
+        let res = sender
+            .send_request(get("/static/a.css", Some("www.example.com")))
+            .await?;
+        assert!(body(res)
+            .await?
+            .starts_with("static /static/a.css www.example.com"));
+        let res = sender
+            .send_request(get("/", Some("www.example.com")))
+            .await?;
+        assert!(body(res).await?.starts_with("web / "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http2_requests() -> Result<()> {
+        let addr = proxy(false).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await?;
+        tokio::spawn(conn);
+
+        let res = sender
+            .send_request(get("http://api.example.com/users", None))
+            .await?;
+        assert_eq!(res.version(), Version::HTTP_2);
+        // upstream 收到的是 HTTP/1.1 的 origin-form 和 Host 头
+        assert!(body(res)
+            .await?
+            .starts_with("api /users api.example.com 10.0.0.1, 127.0.0.1"));
+        let res = sender
+            .send_request(get("http://www.example.com/static/a.css", None))
+            .await?;
+        assert!(body(res).await?.starts_with("static /static/a.css"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn https_requests() -> Result<()> {
+        let addr = proxy(true).await?;
+        // 客户端信任默认证书，ALPN 协商 h2
+        let ca = UpstreamTls {
+            ca: Some(TlsConfig::default().cert),
+            server_name: Some("localhost".into()),
+        };
+        let connector = UpstreamConnector::new(&ca, Protocol::Http2)?;
+        let stream = connector
+            .connect(&addr.to_string(), TcpStream::connect(addr).await?)
+            .await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await?;
+        tokio::spawn(conn);
+
+        let res = sender
+            .send_request(get("https://api.example.com/users", None))
+            .await?;
+        assert_eq!(res.version(), Version::HTTP_2);
+        assert!(body(res)
+            .await?
+            .ends_with("for=127.0.0.1;proto=https;host=\"api.example.com\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http2_upstream_over_tls() -> Result<()> {
+        // upstream 回应收到的 :scheme，按 ALPN 只说一种协议
+        let acceptor = tls::acceptor(&TlsConfig::default(), Mode::Http)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await?;
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let scheme = req.uri().scheme_str().unwrap_or("-").to_owned();
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(scheme))))
+                    });
+                    let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                    let builder = auto::Builder::new(TokioExecutor::new());
+                    let builder = match h2 {
+                        true => builder.http2_only(),
+                        false => builder.http1_only(),
+                    };
+                    let _ = builder
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                    Ok::<_, anyhow::Error>(())
+                });
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind = listener.local_addr()?;
+        let config: Config = toml::from_str(&format!(
+            "[[listeners]]\nbind = \"{bind}\"\nmode = \"http\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]\nprotocol = \"http2\"\ntls = {{ ca = \"{}\", server_name = \"localhost\" }}\nhealth = {{ check = \"http\", fall = 1 }}",
+            TlsConfig::default().cert.display()
+        ))?;
+        let (_, state) = watch::channel(Arc::new(State::new(config, None)?));
+        tokio::spawn(async move {
+            let (client, addr) = listener.accept().await.unwrap();
+            let addrs = Addresses {
+                source: addr,
+                destination: bind,
+            };
+            serve(Box::new(client), addrs, bind, false, state).await;
+        });
+        // 健康检查用 HTTP/1.1 探测，upstream 不能被摘掉
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let stream = TcpStream::connect(bind).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+        let res = sender.send_request(get("/", Some("a.com"))).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await?, "https");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upstream_connections_are_reused() -> Result<()> {
+        let (upstream, accepted) = counting_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind = listener.local_addr()?;
+        let config: Config = toml::from_str(&format!(
+            "[[listeners]]\nbind = \"{bind}\"\nmode = \"http\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]\nkeepalive = {{ size = 1, max_idle = 1 }}"
+        ))?;
+        let (_, state) = watch::channel(Arc::new(State::new(config, None)?));
+        tokio::spawn(async move {
+            while let Ok((client, addr)) = listener.accept().await {
+                let addrs = Addresses {
+                    source: addr,
+                    destination: bind,
+                };
+                tokio::spawn(serve(Box::new(client), addrs, bind, false, state.clone()));
+            }
+        });
+
+        // 每个请求一个客户端连接，只读响应头
+        let request = |path: &'static str| async move {
+            let stream = TcpStream::connect(bind).await?;
+            let (mut sender, conn) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(conn);
+            Ok::<_, anyhow::Error>(sender.send_request(get(path, Some("a.com"))).await?)
+        };
+        let settle = || tokio::time::sleep(std::time::Duration::from_millis(50));
+        // 健康检查启动时也会连接 upstream
+        settle().await;
+        let base = accepted.load(Ordering::SeqCst);
+        let opened = || accepted.load(Ordering::SeqCst) - base;
+
+        for _ in 0..3 {
+            assert_eq!(body(request("/").await?).await?, "ok");
+            settle().await;
+        }
+        assert_eq!(opened(), 1);
+
+        // 响应体还没读完的连接不能给别的请求
+        let slow = request("/slow").await?;
+        assert_eq!(body(request("/").await?).await?, "ok");
+        assert_eq!(opened(), 2);
+        assert_eq!(body(slow).await?, "ok");
+        settle().await;
+
+        // size = 1，两个连接只留下一个，再同时发两个请求要新建一个
+        let slow = request("/slow").await?;
+        assert_eq!(body(request("/").await?).await?, "ok");
+        assert_eq!(opened(), 3);
+        assert_eq!(body(slow).await?, "ok");
+        settle().await;
+
+        // 空闲超过 max_idle 的连接不再使用
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(body(request("/").await?).await?, "ok");
+        assert_eq!(opened(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn access_log_per_request() -> Result<()> {
+        let (upstream, _) = counting_upstream().await;
+        let log = std::env::temp_dir().join(format!("minginx-http-{}.log", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind = listener.local_addr()?;
+        let config: Config = toml::from_str(&format!(
+            "access_log = {{ path = \"{}\", format = \"json\" }}\n[[listeners]]\nbind = \"{bind}\"\nmode = \"http\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]",
+            log.display()
+        ))?;
+        let (_, state) = watch::channel(Arc::new(State::new(config, None)?));
+        tokio::spawn(async move {
+            let (client, addr) = listener.accept().await.unwrap();
+            let addrs = Addresses {
+                source: addr,
+                destination: bind,
+            };
+            serve(Box::new(client), addrs, bind, false, state).await;
+        });
+
+        let stream = TcpStream::connect(bind).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+        let req = Request::post("/upload?a=1")
+            .header(header::HOST, "a.com")
+            .body(Full::new(Bytes::from_static(b"hello")))?;
+        let res = sender.send_request(req).await?;
+        assert_eq!(body(res).await?, "ok");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let line = fs::read_to_string(&log)?;
+        let entry: serde_json::Value = serde_json::from_str(line.trim())?;
+        assert_eq!(entry["upstream"], upstream.to_string());
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["uri"], "/upload?a=1");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes_received"], 5);
+        assert_eq!(entry["bytes_sent"], 2);
+        fs::remove_file(&log)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unavailable_upstream() -> Result<()> {
+        // 没有监听的端口
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let bind = listener.local_addr()?;
+        let config: Config = toml::from_str(&format!(
+            "[[listeners]]\nbind = \"{bind}\"\nmode = \"http\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{closed}\"]\nhealth = {{ fall = 1 }}"
+        ))?;
+        let (_, state) = watch::channel(Arc::new(State::new(config, None)?));
+        tokio::spawn(async move {
+            let (client, addr) = listener.accept().await.unwrap();
+            let addrs = Addresses {
+                source: addr,
+                destination: bind,
+            };
+            serve(Box::new(client), addrs, bind, false, state).await;
+        });
+
+        let stream = TcpStream::connect(bind).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+        let res = sender.send_request(get("/", Some("a.com"))).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(res).await?, "no healthy upstream\n");
+        Ok(())
+    }
+}
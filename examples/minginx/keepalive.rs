@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use hyper::{
+    body::Incoming,
+    client::conn::{http1, http2},
+    Request, Response,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{
+    config::{KeepAliveConfig, Protocol},
+    http::Body,
+    tls::BoxStream,
+};
+
+/// An HTTP connection to an upstream, running in its own task.
+#[derive(Debug)]
+pub struct Connection {
+    sender: Sender,
+    created: Instant,
+    shared: bool, // 从池里共用的 HTTP/2 连接，池里已经有了
+}
+
+#[derive(Debug)]
+enum Sender {
+    Http1(http1::SendRequest<Body>),
+    Http2(http2::SendRequest<Body>),
+}
+
+impl Connection {
+    pub async fn handshake(stream: BoxStream, protocol: Protocol, addr: &str) -> Result<Self> {
+        let io = TokioIo::new(stream);
+        let sender = match protocol {
+            Protocol::Http1 => {
+                let (sender, conn) = http1::handshake(io).await?;
+                tokio::spawn(drive(conn, addr.to_owned()));
+                Sender::Http1(sender)
+            }
+            Protocol::Http2 => {
+                let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+                tokio::spawn(drive(conn, addr.to_owned()));
+                Sender::Http2(sender)
+            }
+        };
+        Ok(Self {
+            sender,
+            created: Instant::now(),
+            shared: false,
+        })
+    }
+
+    /// Another handle of an HTTP/2 connection, which multiplexes the requests.
+    fn share(&self) -> Option<Self> {
+        match &self.sender {
+            Sender::Http1(_) => None,
+            Sender::Http2(sender) => Some(Self {
+                sender: Sender::Http2(sender.clone()),
+                created: self.created,
+                shared: true,
+            }),
+        }
+    }
+
+    pub async fn send_request(&mut self, req: Request<Body>) -> hyper::Result<Response<Incoming>> {
+        match &mut self.sender {
+            Sender::Http1(sender) => sender.send_request(req).await,
+            Sender::Http2(sender) => sender.send_request(req).await,
+        }
+    }
+
+    /// Wait until the connection can take the next request.
+    async fn ready(&mut self) -> hyper::Result<()> {
+        match &mut self.sender {
+            Sender::Http1(sender) => sender.ready().await,
+            Sender::Http2(sender) => sender.ready().await,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        match &self.sender {
+            Sender::Http1(sender) => sender.is_ready(),
+            Sender::Http2(sender) => sender.is_ready(),
+        }
+    }
+}
+
+/// Run an upstream connection until it's closed.
+async fn drive<C, E>(conn: C, addr: String)
+where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    if let Err(e) = conn.await {
+        warn!("Upstream {addr} connection fail with error {e}");
+    }
+}
+
+/// Idle connections to the upstreams of a pool, reused by the next requests.
+#[derive(Debug)]
+pub struct KeepAlive {
+    config: KeepAliveConfig,
+    idle: Mutex<HashMap<String, Vec<Idle>>>, // upstream => 空闲连接，最近放回的在最后
+}
+
+#[derive(Debug)]
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+impl KeepAlive {
+    pub fn new(config: KeepAliveConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The most recently used idle connection to `addr`. HTTP/2 connections
+    /// stay in the pool and are shared by the concurrent requests.
+    pub fn checkout(&self, addr: &str) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        self.expire(&mut idle);
+        let conns = idle.get_mut(addr)?;
+        while let Some(last) = conns.last_mut() {
+            if !last.conn.is_ready() {
+                conns.pop();
+                continue;
+            }
+            if let Some(conn) = last.conn.share() {
+                last.since = Instant::now();
+                return Some(conn);
+            }
+            return conns.pop().map(|idle| idle.conn);
+        }
+        None
+    }
+
+    /// Put `conn` back once it can take the next request, i.e. the response of
+    /// an HTTP/1.1 connection is read. Closed and expired connections are dropped.
+    pub fn checkin(self: &Arc<Self>, addr: String, mut conn: Connection) {
+        if self.config.size == 0 || conn.shared {
+            return;
+        }
+        let keepalive = self.clone();
+        tokio::spawn(async move {
+            if conn.ready().await.is_ok() {
+                keepalive.put(addr, conn);
+            }
+        });
+    }
+
+    fn put(&self, addr: String, conn: Connection) {
+        if conn.created.elapsed() >= self.max_lifetime() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        self.expire(&mut idle);
+        let conns = idle.entry(addr).or_default();
+        if conns.len() < self.config.size {
+            conns.push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    fn expire(&self, idle: &mut HashMap<String, Vec<Idle>>) {
+        let (max_idle, max_lifetime) = (self.max_idle(), self.max_lifetime());
+        idle.retain(|_, conns| {
+            conns.retain(|i| {
+                i.since.elapsed() < max_idle && i.conn.created.elapsed() < max_lifetime
+            });
+            !conns.is_empty()
+        });
+    }
+
+    fn max_idle(&self) -> Duration {
+        Duration::from_secs(self.config.max_idle)
+    }
+
+    fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.config.max_lifetime)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use futures::{stream, StreamExt};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::{
+        body::{Bytes, Frame},
+        service::service_fn,
+    };
+    use hyper_util::server::conn::auto;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::sleep,
+    };
+
+    /// An HTTP/1.1 and HTTP/2 upstream answering `ok`, counting its connections.
+    /// The `k` of `/slow` comes 300ms after the `o`.
+    pub async fn counting_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| async move {
+                        let delay = match req.uri().path() {
+                            "/slow" => Duration::from_millis(300),
+                            _ => Duration::ZERO,
+                        };
+                        let frames = stream::iter([(&b"o"[..], Duration::ZERO), (b"k", delay)])
+                            .then(|(data, delay)| async move {
+                                sleep(delay).await;
+                                Ok::<_, Infallible>(Frame::data(Bytes::from_static(data)))
+                            });
+                        Ok::<_, Infallible>(Response::new(StreamBody::new(frames.boxed())))
+                    });
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    async fn connect(addr: SocketAddr, protocol: Protocol) -> Connection {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Connection::handshake(Box::new(stream), protocol, &addr.to_string())
+            .await
+            .unwrap()
+    }
+
+    async fn round_trip(keepalive: &Arc<KeepAlive>, addr: SocketAddr, protocol: Protocol) {
+        let key = addr.to_string();
+        let mut conn = match keepalive.checkout(&key) {
+            Some(conn) => conn,
+            None => connect(addr, protocol).await,
+        };
+        let req = Request::get(format!("http://{addr}/"))
+            .body(Body::default())
+            .unwrap();
+        let res = conn.send_request(req).await.unwrap();
+        keepalive.checkin(key, conn);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "ok");
+        // 等连接放回池里
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    fn keepalive(size: usize, max_idle: u64, max_lifetime: u64) -> Arc<KeepAlive> {
+        Arc::new(KeepAlive::new(KeepAliveConfig {
+            size,
+            max_idle,
+            max_lifetime,
+        }))
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        for protocol in [Protocol::Http1, Protocol::Http2] {
+            let (addr, accepted) = counting_upstream().await;
+            let keepalive = keepalive(4, 60, 600);
+            for _ in 0..3 {
+                round_trip(&keepalive, addr, protocol).await;
+            }
+            assert_eq!(accepted.load(Ordering::SeqCst), 1, "{protocol:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn http2_connections_are_shared() {
+        let (addr, accepted) = counting_upstream().await;
+        let key = addr.to_string();
+        let keepalive = keepalive(1, 60, 600);
+        let get = |path| {
+            Request::get(format!("http://{addr}{path}"))
+                .body(Body::default())
+                .unwrap()
+        };
+
+        let mut conn = connect(addr, Protocol::Http2).await;
+        let slow = conn.send_request(get("/slow")).await.unwrap();
+        keepalive.checkin(key.clone(), conn);
+        sleep(Duration::from_millis(50)).await;
+
+        // 慢请求还在进行，另外两个请求同时用同一个连接
+        let mut a = keepalive.checkout(&key).unwrap();
+        let mut b = keepalive.checkout(&key).unwrap();
+        let (a, b) = tokio::join!(a.send_request(get("/")), b.send_request(get("/")));
+        for res in [a.unwrap(), b.unwrap(), slow] {
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "ok");
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn size_limits_idle_connections() {
+        let (addr, accepted) = counting_upstream().await;
+        let disabled = keepalive(0, 60, 600);
+        round_trip(&disabled, addr, Protocol::Http1).await;
+        round_trip(&disabled, addr, Protocol::Http1).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let one = keepalive(1, 60, 600);
+        for _ in 0..2 {
+            one.put(addr.to_string(), connect(addr, Protocol::Http1).await);
+        }
+        assert!(one.checkout(&addr.to_string()).is_some());
+        assert!(one.checkout(&addr.to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_connections_are_dropped() {
+        let (addr, _) = counting_upstream().await;
+        let key = addr.to_string();
+        let idle = keepalive(4, 1, 600);
+        idle.put(key.clone(), connect(addr, Protocol::Http1).await);
+        sleep(Duration::from_millis(1100)).await;
+        assert!(idle.checkout(&key).is_none());
+
+        let lifetime = keepalive(4, 60, 1);
+        let conn = connect(addr, Protocol::Http1).await;
+        sleep(Duration::from_millis(1100)).await;
+        lifetime.put(key.clone(), conn);
+        assert!(lifetime.checkout(&key).is_none());
+    }
+}
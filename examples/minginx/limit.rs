@@ -0,0 +1,274 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use strum::Display;
+use tokio::time::Instant;
+
+use crate::config::LimitsConfig;
+
+/// Forget the clients without connections once there are that many.
+const PRUNE_AT: usize = 1024;
+
+/// Why a connection is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+pub enum Rejection {
+    #[strum(serialize = "address denied")]
+    Denied,
+    #[strum(serialize = "too many connections")]
+    TooManyConnections,
+    #[strum(serialize = "too many connections from the address")]
+    TooManyConnectionsPerIp,
+    #[strum(serialize = "accept rate exceeded")]
+    RateExceeded,
+    #[strum(serialize = "accept rate of the address exceeded")]
+    RateExceededPerIp,
+}
+
+/// Counts the connections of all listeners, kept across reloads.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    counters: Mutex<Counters>,
+    rejected: Mutex<HashMap<Rejection, u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    active: usize,
+    bucket: Bucket,
+    clients: HashMap<IpAddr, Client>,
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    active: usize,
+    bucket: Bucket,
+}
+
+/// Token bucket holding up to one second of the rate.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        // 第一次 take 时按速率截断
+        Self {
+            tokens: f64::INFINITY,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, rate: Option<u32>) -> bool {
+        let Some(rate) = rate else {
+            return true;
+        };
+        self.refill(rate);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Give back the token of a connection refused by another limit.
+    fn refund(&mut self, rate: Option<u32>) {
+        if rate.is_some() {
+            self.tokens += 1.0;
+        }
+    }
+
+    fn is_full(&mut self, rate: Option<u32>) -> bool {
+        match rate {
+            Some(rate) => {
+                self.refill(rate);
+                self.tokens >= rate as f64
+            }
+            None => true,
+        }
+    }
+}
+
+/// A connection counted by the limiter until it's dropped.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counters = self.limiter.counters.lock().unwrap();
+        counters.active -= 1;
+        if let Some(client) = counters.clients.get_mut(&self.ip) {
+            client.active -= 1;
+        }
+    }
+}
+
+impl Limiter {
+    /// Admit a connection from `ip` under `limits`.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr, limits: &LimitsConfig) -> Result<Permit, Rejection> {
+        let result = self.check(ip, limits);
+        if let Err(rejection) = result {
+            *self.rejected.lock().unwrap().entry(rejection).or_default() += 1;
+        }
+        result
+    }
+
+    /// Connections refused since the last call, by reason, most frequent first.
+    pub fn take_rejected(&self) -> Vec<(Rejection, u64)> {
+        let mut rejected: Vec<_> = self.rejected.lock().unwrap().drain().collect();
+        rejected.sort_by_key(|&(_, n)| Reverse(n));
+        rejected
+    }
+
+    fn check(self: &Arc<Self>, ip: IpAddr, limits: &LimitsConfig) -> Result<Permit, Rejection> {
+        if !limits.allows(ip) {
+            return Err(Rejection::Denied);
+        }
+        let mut counters = self.counters.lock().unwrap();
+        if counters.clients.len() >= PRUNE_AT {
+            let rate = limits.accept_rate_per_ip;
+            counters
+                .clients
+                .retain(|_, client| client.active > 0 || !client.bucket.is_full(rate));
+        }
+        if limits
+            .max_connections
+            .is_some_and(|max| counters.active >= max)
+        {
+            return Err(Rejection::TooManyConnections);
+        }
+        let Counters {
+            active,
+            bucket,
+            clients,
+        } = &mut *counters;
+        let client = clients.entry(ip).or_default();
+        if limits
+            .max_connections_per_ip
+            .is_some_and(|max| client.active >= max)
+        {
+            return Err(Rejection::TooManyConnectionsPerIp);
+        }
+        // 先按地址限速，超速的客户端不消耗全局的令牌
+        if !client.bucket.take(limits.accept_rate_per_ip) {
+            return Err(Rejection::RateExceededPerIp);
+        }
+        if !bucket.take(limits.accept_rate) {
+            client.bucket.refund(limits.accept_rate_per_ip);
+            return Err(Rejection::RateExceeded);
+        }
+        *active += 1;
+        client.active += 1;
+        Ok(Permit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(s: &str) -> LimitsConfig {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let limiter = Arc::new(Limiter::default());
+        let limits = limits(
+            r#"allow = ["10.0.0.0/8", "::1/128"]
+deny = ["10.1.0.0/16"]"#,
+        );
+        let admit = |ip: &str| limiter.admit(ip.parse().unwrap(), &limits).map(|_| ());
+        assert_eq!(admit("10.0.0.1"), Ok(()));
+        assert_eq!(admit("::1"), Ok(()));
+        assert_eq!(admit("10.1.0.1"), Err(Rejection::Denied));
+        assert_eq!(admit("192.168.0.1"), Err(Rejection::Denied));
+    }
+
+    #[test]
+    fn connection_limits() {
+        let limiter = Arc::new(Limiter::default());
+        let limits = limits("max_connections = 3\nmax_connections_per_ip = 2");
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let first = limiter.admit(a, &limits).unwrap();
+        let _second = limiter.admit(a, &limits).unwrap();
+        assert_eq!(
+            limiter.admit(a, &limits).unwrap_err(),
+            Rejection::TooManyConnectionsPerIp
+        );
+        let _third = limiter.admit(b, &limits).unwrap();
+        assert_eq!(
+            limiter.admit(b, &limits).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+
+        // 连接关闭后释放名额
+        drop(first);
+        assert!(limiter.admit(a, &limits).is_ok());
+
+        // 拒绝的连接汇总后清零
+        let rejected: HashMap<_, _> = limiter.take_rejected().into_iter().collect();
+        assert_eq!(
+            rejected,
+            HashMap::from([
+                (Rejection::TooManyConnectionsPerIp, 1),
+                (Rejection::TooManyConnections, 1),
+            ])
+        );
+        assert!(limiter.take_rejected().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accept_rates() {
+        let limiter = Arc::new(Limiter::default());
+        let limits = limits("accept_rate = 4\naccept_rate_per_ip = 2");
+        let [a, b, c] = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].map(|ip| ip.parse().unwrap());
+
+        // 许可立即释放，只有速率起作用
+        assert!(limiter.admit(a, &limits).is_ok());
+        assert!(limiter.admit(a, &limits).is_ok());
+        // 超速的客户端一直重试也不影响别人
+        for _ in 0..10 {
+            assert_eq!(
+                limiter.admit(a, &limits).unwrap_err(),
+                Rejection::RateExceededPerIp
+            );
+        }
+        assert!(limiter.admit(b, &limits).is_ok());
+        assert!(limiter.admit(b, &limits).is_ok());
+        assert_eq!(
+            limiter.admit(c, &limits).unwrap_err(),
+            Rejection::RateExceeded
+        );
+
+        // 等令牌按速率补充，c 被拒绝时没有消耗自己的令牌
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert!(limiter.admit(c, &limits).is_ok());
+        assert!(limiter.admit(c, &limits).is_ok());
+        assert_eq!(
+            limiter.admit(a, &limits).unwrap_err(),
+            Rejection::RateExceeded
+        );
+    }
+}
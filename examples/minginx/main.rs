@@ -0,0 +1,150 @@
+mod access_log;
+mod balance;
+mod config;
+mod copy;
+mod health;
+mod http;
+mod keepalive;
+mod limit;
+mod proxy_protocol;
+mod server;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+use access_log::Entry;
+use anyhow::Result;
+use balance::{Balancer, Lease};
+use health::FALLBACK_RESPONSE;
+use proxy_protocol::Addresses;
+use server::{Pool, Server};
+use std::{env, net::IpAddr, time::Duration};
+use tls::BoxStream;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+
+const DEFAULT_CONFIG: &str = "examples/minginx/minginx.toml";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    dotenv::dotenv().ok();
+    let path = env::var("MINGINX_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG.to_owned());
+    // upstream 不可用时不再退出，由健康检查摘除，恢复后重新加入
+    let server = Server::start(&path).await?;
+    info!("Config {path} loaded, send SIGHUP or edit it to reload");
+    server.run().await
+}
+
+/// Proxy `client` to a healthy upstream of `pool`, or answer with the fallback
+/// response if there is none. The connection is closed after `idle` without
+/// traffic, `entry` gets the upstream and the bytes copied.
+async fn proxy(
+    mut client: BoxStream,
+    addrs: Addresses,
+    pool: &Pool,
+    idle: Duration,
+    entry: &mut Entry,
+) -> Result<()> {
+    let addr = addrs.source;
+    // lease 在连接结束前一直持有，least_conn 靠它计数
+    let Some((lease, upstream)) = pool.connect(addrs).await? else {
+        warn!("No healthy upstream for client {addr}");
+        client.write_all(FALLBACK_RESPONSE).await?;
+        client.shutdown().await?;
+        return Ok(());
+    };
+
+    entry.upstream = Some(lease.addr.clone());
+    let transferred = &mut entry.transferred;
+    let result = copy::copy_bidirectional(client, upstream, idle, transferred).await;
+    info!(
+        "Proxy client {addr} upstream {} closed, {} bytes from client to upstream, {} bytes from upstream to client",
+        lease.addr, transferred.client_to_upstream, transferred.upstream_to_client
+    );
+    Ok(result?)
+}
+
+/// Connect to a healthy upstream, counting a failed connect against the upstream
+/// and trying the next one.
+async fn connect_upstream(
+    balancer: &Balancer,
+    ip: IpAddr,
+    limit: Duration,
+) -> Option<(Lease, TcpStream)> {
+    for _ in 0..balancer.upstreams().len() {
+        let lease = balancer.pick(ip)?;
+        if let Ok(upstream) = connect_lease(balancer, &lease, limit).await {
+            return Some((lease, upstream));
+        }
+    }
+    None
+}
+
+/// Connect to the upstream of `lease`, reporting the result to its health.
+async fn connect_lease(
+    balancer: &Balancer,
+    lease: &Lease,
+    limit: Duration,
+) -> io::Result<TcpStream> {
+    let result = match timeout(limit, TcpStream::connect(&lease.addr)).await {
+        Ok(result) => result,
+        Err(e) => Err(io::Error::new(io::ErrorKind::TimedOut, e)),
+    };
+    health::report(balancer, lease, result.as_ref());
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod test {
+    #[tokio::test]
+    async fn test_tokio_try_join() {
+        async fn do_stuff_async() -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn more_async_work() -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        let res = tokio::try_join!(do_stuff_async(), more_async_work());
+
+        match res {
+            Ok((_, _)) => {
+                assert!(true, "join")
+            }
+            Err(err) => {
+                println!("processing failed; error = {}", err);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokio_try_join_with_error() {
+        async fn do_stuff_async() -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("do_stuff_async error"))
+        }
+
+        async fn more_async_work() -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("more_async_work error"))
+        }
+
+        let res = tokio::try_join!(do_stuff_async(), more_async_work());
+
+        match res {
+            Ok((_, _)) => {}
+            Err(err) => {
+                println!("processing failed; error = {}", err);
+                assert!(true)
+            }
+        }
+    }
+}
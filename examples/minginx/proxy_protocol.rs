@@ -0,0 +1,243 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyProtocol;
+
+/// Beginning of a v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header with the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Where a client connection comes from and goes to, as told by a PROXY header
+/// or seen on the socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// The PROXY header telling an upstream about the client connection `addrs`.
+pub fn header(version: ProxyProtocol, addrs: Addresses) -> Vec<u8> {
+    let (source, destination) = (addrs.source, addrs.destination);
+    // 两个地址的协议族不同时都用 IPv6
+    let v4 = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => Some((s, d)),
+        _ => None,
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let (family, source_ip, destination_ip) = match v4 {
+                Some((s, d)) => ("TCP4", s.to_string(), d.to_string()),
+                None => (
+                    "TCP6",
+                    v6(source.ip()).to_string(),
+                    v6(destination.ip()).to_string(),
+                ),
+            };
+            format!(
+                "PROXY {family} {source_ip} {destination_ip} {} {}\r\n",
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let (family, ips) = match v4 {
+                Some((s, d)) => (0x11, [s.octets(), d.octets()].concat()),
+                None => {
+                    let ips = [v6(source.ip()).octets(), v6(destination.ip()).octets()];
+                    (0x21, ips.concat())
+                }
+            };
+            let len = (ips.len() + 4) as u16;
+            let mut buf = V2_SIGNATURE.to_vec();
+            // 版本 2，PROXY 命令，TCP
+            buf.extend([0x21, family]);
+            buf.extend(len.to_be_bytes());
+            buf.extend(ips);
+            buf.extend(source.port().to_be_bytes());
+            buf.extend(destination.port().to_be_bytes());
+            buf
+        }
+    }
+}
+
+/// The header of a connection not proxied for a client, e.g. a health probe.
+pub fn local_header(version: ProxyProtocol) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        ProxyProtocol::V2 => [&V2_SIGNATURE[..], &[0x20, 0x00, 0x00, 0x00]].concat(),
+    }
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Read the v1 or v2 PROXY header a load balancer sends first, without reading
+/// past it. `None` for the connections of the load balancer itself.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<Addresses>> {
+    // 两个版本的头都不止 8 字节
+    let mut start = [0u8; 8];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE[..8] {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start).await
+    } else {
+        Err(anyhow!("no PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    start: [u8; 8],
+) -> Result<Option<Addresses>> {
+    // 逐字节读到 CRLF，后面是客户端的数据
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY v1 header longer than {V1_MAX_LEN} bytes"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&line[..line.len() - 2])?)
+}
+
+fn parse_v1(line: &str) -> Result<Option<Addresses>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let source: IpAddr = source.parse()?;
+            let destination: IpAddr = destination.parse()?;
+            if source.is_ipv4() != (family == "TCP4") || destination.is_ipv4() != source.is_ipv4() {
+                return Err(anyhow!(
+                    "addresses of PROXY v1 header {line:?} aren't {family}"
+                ));
+            }
+            Ok(Some(Addresses {
+                source: SocketAddr::new(source, source_port.parse()?),
+                destination: SocketAddr::new(destination, destination_port.parse()?),
+            }))
+        }
+        _ => Err(anyhow!("invalid PROXY v1 header {line:?}")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<Addresses>> {
+    let mut rest = [0u8; 8];
+    stream.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] {
+        return Err(anyhow!("invalid PROXY v2 signature"));
+    }
+    let (version_command, family) = (rest[4], rest[5]);
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(anyhow!(
+            "unsupported PROXY version {}",
+            version_command >> 4
+        ));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None), // LOCAL
+        1 => {}
+        command => return Err(anyhow!("unknown PROXY v2 command {command}")),
+    }
+    // 地址后面的 TLV 忽略
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let addrs = match family >> 4 {
+        1 if len >= 12 => {
+            let ip = |at: usize| Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]);
+            Addresses {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            }
+        }
+        2 if len >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = body[at..at + 16].try_into().unwrap();
+                Ipv6Addr::from(octets)
+            };
+            Addresses {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            }
+        }
+        // AF_UNSPEC、AF_UNIX 没有可用的地址
+        0 | 3 => return Ok(None),
+        _ => return Err(anyhow!("invalid PROXY v2 address family {family:#x}")),
+    };
+    Ok(Some(addrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn v1_header() {
+        let v4 = addrs("192.168.0.1:56324", "10.0.0.2:443");
+        assert_eq!(
+            header(ProxyProtocol::V1, v4),
+            b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\n"
+        );
+        let mixed = addrs("192.168.0.1:56324", "[::1]:443");
+        assert_eq!(
+            header(ProxyProtocol::V1, mixed),
+            b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> Result<()> {
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            for addrs in [
+                addrs("192.168.0.1:56324", "10.0.0.2:443"),
+                addrs("[2001:db8::1]:56324", "[::1]:8443"),
+            ] {
+                // 头后面的数据留给应用
+                let mut data = header(version, addrs);
+                data.extend(b"GET /");
+                let mut stream = &data[..];
+                assert_eq!(read_header(&mut stream).await?, Some(addrs), "{version:?}");
+                assert_eq!(stream, b"GET /");
+            }
+            let local = local_header(version);
+            assert_eq!(read_header(&mut &local[..]).await?, None, "{version:?}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_are_an_error() {
+        let cases: [&[u8]; 5] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 ::1 ::1 1 2\r\n",
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n",
+            &[b'A'; 200],
+            &[&V2_SIGNATURE[..], &[0x31, 0x11, 0x00, 0x00]].concat(),
+        ];
+        for case in cases {
+            assert!(read_header(&mut &case[..]).await.is_err(), "{case:?}");
+        }
+        let long = [&b"PROXY "[..], &[b'1'; 200]].concat();
+        assert!(read_header(&mut &long[..]).await.is_err());
+    }
+}
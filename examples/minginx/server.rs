@@ -0,0 +1,780 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use crate::{
+    access_log::{AccessLog, Entry},
+    balance::{Balancer, Lease},
+    config::{Config, Mode, PoolConfig, Protocol},
+    health, http,
+    keepalive::{Connection, KeepAlive},
+    limit::{Limiter, Permit},
+    proxy_protocol::{self, Addresses},
+    tls::{self, BoxStream, UpstreamConnector},
+};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the rejected connections are summed up in the log.
+const REJECTED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// An upstream pool with its health checker, which stops when the pool is dropped.
+#[derive(Debug)]
+pub struct Pool {
+    pub config: PoolConfig,
+    pub balancer: Arc<Balancer>,
+    tls: Option<UpstreamConnector>,
+    keepalive: Arc<KeepAlive>,
+    checker: JoinHandle<()>,
+}
+
+impl Pool {
+    fn new(config: PoolConfig) -> Result<Self> {
+        let health = &config.health;
+        let balancer = Arc::new(
+            Balancer::new(config.upstreams.clone(), config.strategy)
+                .thresholds(health.fall, health.rise),
+        );
+        let tls = config
+            .tls
+            .as_ref()
+            .map(|tls| UpstreamConnector::new(tls, config.protocol))
+            .transpose()?;
+        let mut checker = health.checker();
+        // 探测发 HTTP/1.1 请求，不能用 ALPN 只协商 h2 的连接器
+        checker.tls = config
+            .tls
+            .as_ref()
+            .map(|tls| UpstreamConnector::new(tls, Protocol::Http1))
+            .transpose()?;
+        checker.proxy_protocol = config.proxy_protocol;
+        let checker = tokio::spawn(checker.run(balancer.clone()));
+        let keepalive = Arc::new(KeepAlive::new(config.keepalive.clone()));
+        Ok(Self {
+            config,
+            balancer,
+            tls,
+            keepalive,
+            checker,
+        })
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.health.timeout)
+    }
+
+    /// Connect to a healthy upstream for the client connection `addrs`, over TLS
+    /// if the pool re-encrypts. `None` if there is no healthy upstream.
+    pub async fn connect(&self, addrs: Addresses) -> Result<Option<(Lease, BoxStream)>> {
+        let limit = self.connect_timeout();
+        let ip = addrs.source.ip();
+        let Some((lease, upstream)) = crate::connect_upstream(&self.balancer, ip, limit).await
+        else {
+            return Ok(None);
+        };
+        let upstream = self.handshake(&lease, upstream, addrs).await?;
+        Ok(Some((lease, upstream)))
+    }
+
+    /// An idle or new HTTP connection to a healthy upstream, `None` if there is
+    /// no healthy upstream. Give it back with [`Pool::checkin`].
+    pub async fn http_connection(&self, addrs: Addresses) -> Result<Option<(Lease, Connection)>> {
+        let limit = self.connect_timeout();
+        for _ in 0..self.balancer.upstreams().len() {
+            let Some(lease) = self.balancer.pick(addrs.source.ip()) else {
+                return Ok(None);
+            };
+            if let Some(conn) = self.keepalive.checkout(&lease.addr) {
+                return Ok(Some((lease, conn)));
+            }
+            let Ok(upstream) = crate::connect_lease(&self.balancer, &lease, limit).await else {
+                continue;
+            };
+            let upstream = self.handshake(&lease, upstream, addrs).await?;
+            let conn = Connection::handshake(upstream, self.config.protocol, &lease.addr).await?;
+            return Ok(Some((lease, conn)));
+        }
+        Ok(None)
+    }
+
+    /// Keep `conn` to `addr` for the next requests.
+    pub fn checkin(&self, addr: &str, conn: Connection) {
+        // PROXY 头只说明了一个客户端，连接不能给别的客户端用
+        if self.config.proxy_protocol.is_none() {
+            self.keepalive.checkin(addr.to_owned(), conn);
+        }
+    }
+
+    /// Send the PROXY header if the pool is configured to, then TLS handshake
+    /// with the upstream if the pool re-encrypts.
+    async fn handshake(
+        &self,
+        lease: &Lease,
+        mut upstream: TcpStream,
+        addrs: Addresses,
+    ) -> Result<BoxStream> {
+        if let Some(version) = self.config.proxy_protocol {
+            upstream
+                .write_all(&proxy_protocol::header(version, addrs))
+                .await?;
+        }
+        let Some(tls) = &self.tls else {
+            return Ok(Box::new(upstream));
+        };
+        let limit = self.connect_timeout();
+        let result = match timeout(limit, tls.connect(&lease.addr, upstream)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("TLS handshake timeout after {limit:?}")),
+        };
+        // 握手失败也算 upstream 的一次失败
+        if let Err(e) = &result {
+            health::report(&self.balancer, lease, Err::<(), _>(e));
+        }
+        result
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.checker.abort();
+    }
+}
+
+/// The config in effect and the pools, TLS acceptors and access log built from
+/// it. Accepted connections keep their upstream, a reload only affects new
+/// connections.
+pub struct State {
+    pub config: Config,
+    pub pools: HashMap<String, Arc<Pool>>,
+    pub access_log: Option<Arc<AccessLog>>,
+    acceptors: HashMap<SocketAddr, TlsAcceptor>, // bind => acceptor
+}
+
+impl State {
+    /// Build the pools of `config`, keeping the unchanged pools of `old` with their
+    /// connection counts and health.
+    pub fn new(config: Config, old: Option<&State>) -> Result<Self> {
+        let mut pools = HashMap::new();
+        for (name, pool_config) in &config.pools {
+            let pool = match old
+                .and_then(|old| old.pools.get(name))
+                .filter(|pool| pool.config == *pool_config)
+            {
+                Some(pool) => pool.clone(),
+                None => Arc::new(Pool::new(pool_config.clone())?),
+            };
+            pools.insert(name.clone(), pool);
+        }
+        let access_log = match &config.access_log {
+            Some(log_config) => match old
+                .and_then(|old| old.access_log.as_ref())
+                .filter(|log| log.config() == log_config)
+            {
+                Some(log) => Some(log.clone()),
+                None => Some(Arc::new(AccessLog::open(log_config)?)),
+            },
+            None => None,
+        };
+        // 证书每次重载都重新读取，方便更换证书
+        let mut acceptors = HashMap::new();
+        for listener in &config.listeners {
+            if let Some(tls_config) = &listener.tls {
+                acceptors.insert(listener.bind, tls::acceptor(tls_config, listener.mode)?);
+            }
+        }
+        Ok(Self {
+            config,
+            pools,
+            access_log,
+            acceptors,
+        })
+    }
+
+    /// Pool serving a client from `ip` on the listener `bind`, `host` and `path`
+    /// are the ones of the request in `http` mode.
+    pub fn pool(
+        &self,
+        bind: SocketAddr,
+        ip: IpAddr,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Option<&Arc<Pool>> {
+        let listener = self.config.listener(bind)?;
+        self.pools.get(listener.route(ip, host, path))
+    }
+}
+
+/// Owns the listeners, reloads the config on SIGHUP or when the file changes.
+pub struct Server {
+    path: PathBuf,
+    state: watch::Sender<Arc<State>>,
+    limiter: Arc<Limiter>,
+    listeners: HashMap<SocketAddr, (SocketAddr, JoinHandle<()>)>, // bind => (local addr, accept loop)
+}
+
+impl Server {
+    pub async fn start(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let config = Config::from_file(&path)?;
+        let (state, _) = watch::channel(Arc::new(State::new(config, None)?));
+        let mut server = Self {
+            path,
+            state,
+            limiter: Arc::new(Limiter::default()),
+            listeners: HashMap::new(),
+        };
+        server.bind().await?;
+        Ok(server)
+    }
+
+    /// Local address of the listener configured as `bind`.
+    #[cfg(test)]
+    pub fn local_addr(&self, bind: SocketAddr) -> Option<SocketAddr> {
+        self.listeners.get(&bind).map(|(addr, _)| *addr)
+    }
+
+    /// Read the config file again, an invalid config leaves everything as it was.
+    /// The certificates are read again even when the config is unchanged.
+    pub async fn reload(&mut self) -> Result<()> {
+        let config = Config::from_file(&self.path)?;
+        let state = State::new(config, Some(&self.state.borrow()))?;
+        let old = self.state.send_replace(Arc::new(state));
+        if let Err(e) = self.bind().await {
+            // 新的监听地址绑定失败，回滚
+            self.state.send_replace(old);
+            self.bind().await?;
+            return Err(e);
+        }
+        info!("Config {} reloaded", self.path.display());
+        Ok(())
+    }
+
+    /// Start the listeners of the current config and stop the ones it dropped,
+    /// connections already accepted carry on.
+    async fn bind(&mut self) -> Result<()> {
+        let state = self.state.borrow().clone();
+        let mut started: HashMap<SocketAddr, (SocketAddr, JoinHandle<()>)> = HashMap::new();
+        for listener_config in &state.config.listeners {
+            let bind = listener_config.bind;
+            if self.listeners.contains_key(&bind) {
+                continue;
+            }
+            let listener = match TcpListener::bind(bind).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    for (_, handle) in started.into_values() {
+                        handle.abort();
+                    }
+                    return Err(anyhow!("Listen on {bind} fail with error {e}"));
+                }
+            };
+            let local_addr = listener.local_addr()?;
+            info!("Service listen on {local_addr}");
+            let handle = tokio::spawn(accept(
+                listener,
+                bind,
+                self.state.subscribe(),
+                self.limiter.clone(),
+            ));
+            started.insert(bind, (local_addr, handle));
+        }
+        self.listeners.extend(started);
+        self.listeners.retain(|bind, (local_addr, handle)| {
+            let keep = state.config.listener(*bind).is_some();
+            if !keep {
+                info!("Stop listening on {local_addr}");
+                handle.abort();
+            }
+            keep
+        });
+        Ok(())
+    }
+
+    /// Log how many connections the limits refused, a flood of clients makes a
+    /// single line.
+    fn report_rejected(&self) {
+        let rejected = self.limiter.take_rejected();
+        if rejected.is_empty() {
+            return;
+        }
+        let reasons: Vec<String> = rejected
+            .iter()
+            .map(|(rejection, n)| format!("{n} {rejection}"))
+            .collect();
+        warn!(
+            "Rejected clients in the last {}s: {}",
+            REJECTED_REPORT_INTERVAL.as_secs(),
+            reasons.join(", ")
+        );
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        #[cfg(unix)]
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let mut modified = modified_at(&self.path);
+        let mut watch = tokio::time::interval(WATCH_INTERVAL);
+        let mut report = tokio::time::interval(REJECTED_REPORT_INTERVAL);
+        loop {
+            // 没有 SIGHUP 的平台只靠监视文件修改时间
+            #[cfg(unix)]
+            let hangup = hangups.recv();
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+            tokio::select! {
+                _ = hangup => info!("SIGHUP received, reload {}", self.path.display()),
+                _ = report.tick() => {
+                    self.report_rejected();
+                    continue;
+                }
+                _ = watch.tick() => {
+                    let now = modified_at(&self.path);
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("Config {} changed", self.path.display());
+                }
+            }
+            if let Err(e) = self.reload().await {
+                warn!("Reload fail with error {e:#}, keep the current config");
+            }
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+async fn accept(
+    listener: TcpListener,
+    bind: SocketAddr,
+    state: watch::Receiver<Arc<State>>,
+    limiter: Arc<Limiter>,
+) {
+    loop {
+        let (mut client, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Accept on {bind} fail with error {e}");
+                // EMFILE 等错误会立即再次出现，不等待就会空转
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let current = state.borrow().clone();
+        let Some(listener_config) = current.config.listener(bind) else {
+            continue;
+        };
+        let accept_proxy_protocol = listener_config.proxy_protocol;
+        // 有 PROXY 头时按头里的客户端地址限制，读到头之后再检查
+        let permit = if accept_proxy_protocol {
+            None
+        } else {
+            match admit(&limiter, peer, &current, bind) {
+                Some(permit) => Some(permit),
+                None => continue,
+            }
+        };
+        info!("Accept client {}", &peer);
+        let (mode, idle) = (
+            listener_config.mode,
+            Duration::from_secs(listener_config.idle_timeout),
+        );
+        let acceptor = current.acceptors.get(&bind).cloned();
+        let access_log = current.access_log.clone();
+        let state = state.clone();
+        let limiter = limiter.clone();
+        tokio::spawn(async move {
+            let mut addrs = Addresses {
+                source: peer,
+                destination: client.local_addr().unwrap_or(bind),
+            };
+            if accept_proxy_protocol {
+                // PROXY 头在 TLS 握手之前
+                match timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header(&mut client)).await {
+                    Ok(Ok(Some(header))) => {
+                        info!("Client {peer} is {} by PROXY header", header.source);
+                        addrs = header;
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => {
+                        warn!("PROXY header from {peer} fail with error {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("PROXY header from {peer} timeout");
+                        return;
+                    }
+                }
+            }
+            // 之后都用真实的客户端地址
+            let addr = addrs.source;
+            // 连接结束时释放名额
+            let _permit = match permit {
+                Some(permit) => permit,
+                None => {
+                    let current = state.borrow().clone();
+                    match admit(&limiter, addr, &current, bind) {
+                        Some(permit) => permit,
+                        None => return,
+                    }
+                }
+            };
+            let tls = acceptor.is_some();
+            let client: BoxStream = match acceptor {
+                Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(client)).await {
+                    Ok(Ok(stream)) => Box::new(stream),
+                    Ok(Err(e)) => {
+                        warn!("TLS handshake with client {addr} fail with error {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("TLS handshake with client {addr} timeout");
+                        return;
+                    }
+                },
+                None => Box::new(client),
+            };
+            if mode == Mode::Http {
+                // http 模式按请求路由
+                http::serve(client, addrs, bind, tls, state).await;
+                return;
+            }
+            // 每个连接取当时生效的配置
+            let pool = state.borrow().pool(bind, addr.ip(), None, None).cloned();
+            let Some(pool) = pool else {
+                warn!("No pool for client {addr} on {bind}");
+                return;
+            };
+            let mut entry = Entry::new(addr, bind);
+            if let Err(e) = crate::proxy(client, addrs, &pool, idle, &mut entry).await {
+                warn!("Proxy client {addr} fail with error {e}");
+            }
+            if let Some(log) = access_log {
+                log.write(&entry);
+            }
+        });
+    }
+}
+
+/// Count the connection of `client` under the limits of `state`, `None` when
+/// it's refused.
+fn admit(
+    limiter: &Arc<Limiter>,
+    client: SocketAddr,
+    state: &State,
+    bind: SocketAddr,
+) -> Option<Permit> {
+    // 双栈监听时 IPv4 客户端是 ::ffff:a.b.c.d
+    match limiter.admit(client.ip().to_canonical(), &state.config.limits) {
+        Ok(permit) => Some(permit),
+        Err(rejection) => {
+            // 每个连接只记 debug，定期汇总成一条 warn
+            debug!("Reject client {client} on {bind}: {rejection}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{TlsConfig, UpstreamTls},
+        testing::{echo_server, self_signed},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    fn write_config(path: &Path, bind: &str, upstream: SocketAddr) {
+        let s = format!(
+            "[[listeners]]\nbind = \"{bind}\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]\n"
+        );
+        fs::write(path, s).unwrap();
+    }
+
+    async fn read_name(stream: &mut TcpStream) -> String {
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf).to_string()
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_connections() -> Result<()> {
+        let a = echo_server("a", None).await;
+        let b = echo_server("b", None).await;
+        let path = std::env::temp_dir().join(format!("minginx-{}.toml", std::process::id()));
+        let bind: SocketAddr = "127.0.0.1:0".parse()?;
+        write_config(&path, "127.0.0.1:0", a);
+
+        let mut server = Server::start(&path).await?;
+        let addr = server.local_addr(bind).unwrap();
+        let mut first = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut first).await, "a");
+
+        write_config(&path, "127.0.0.1:0", b);
+        server.reload().await?;
+        let mut second = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut second).await, "b");
+
+        // 重载前建立的连接仍然连着 a
+        first.write_all(b"x").await?;
+        assert_eq!(read_name(&mut first).await, "x");
+
+        // 无效配置不生效
+        fs::write(&path, "listeners = []\n[pools]")?;
+        assert!(server.reload().await.is_err());
+        let mut third = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut third).await, "b");
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Whether the proxy closed `stream` without a byte from the upstream.
+    async fn is_rejected(stream: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1];
+        matches!(stream.read(&mut buf).await, Ok(0) | Err(_))
+    }
+
+    #[tokio::test]
+    async fn limits_reject_clients() -> Result<()> {
+        let upstream = echo_server("a", None).await;
+        let path = std::env::temp_dir().join(format!("minginx-limits-{}.toml", std::process::id()));
+        let config = |limits: &str| {
+            format!(
+                "[limits]\n{limits}\n[[listeners]]\nbind = \"127.0.0.1:0\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]\n"
+            )
+        };
+        fs::write(&path, config("max_connections_per_ip = 1"))?;
+        let mut server = Server::start(&path).await?;
+        let addr = server.local_addr("127.0.0.1:0".parse()?).unwrap();
+
+        let mut first = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut first).await, "a");
+        let mut second = TcpStream::connect(addr).await?;
+        assert!(is_rejected(&mut second).await);
+
+        // 连接关闭后名额释放
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut third).await, "a");
+
+        // 重载后仍然记得已有的连接
+        fs::write(
+            &path,
+            config("max_connections = 1\nallow = [\"127.0.0.0/8\"]"),
+        )?;
+        server.reload().await?;
+        let mut fourth = TcpStream::connect(addr).await?;
+        assert!(is_rejected(&mut fourth).await);
+
+        fs::write(&path, config("deny = [\"127.0.0.1/32\"]"))?;
+        server.reload().await?;
+        let mut denied = TcpStream::connect(addr).await?;
+        assert!(is_rejected(&mut denied).await);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn access_log_per_connection() -> Result<()> {
+        let upstream = echo_server("a", None).await;
+        let dir = std::env::temp_dir();
+        let log = dir.join(format!("minginx-tcp-{}.log", std::process::id()));
+        let path = dir.join(format!("minginx-log-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "[access_log]\npath = \"{}\"\nformat = \"common\"\n[[listeners]]\nbind = \"127.0.0.1:0\"\npool = \"p\"\n[pools.p]\nupstreams = [\"{upstream}\"]\n",
+                log.display()
+            ),
+        )?;
+        let server = Server::start(&path).await?;
+        let addr = server.local_addr("127.0.0.1:0".parse()?).unwrap();
+
+        let mut client = TcpStream::connect(addr).await?;
+        assert_eq!(read_name(&mut client).await, "a");
+        client.write_all(b"hello").await?;
+        client.shutdown().await?;
+        let mut echo = vec![];
+        client.read_to_end(&mut echo).await?;
+        assert_eq!(echo, b"hello");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 客户端收到 6 字节，upstream 收到 5 字节
+        let line = fs::read_to_string(&log)?;
+        assert!(line.starts_with("127.0.0.1 - - ["), "{line}");
+        assert!(
+            line.contains(&format!("] \"-\" - 6 {upstream} 5 ")),
+            "{line}"
+        );
+        fs::remove_file(&path)?;
+        fs::remove_file(&log)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_keeps_client_addr() -> Result<()> {
+        // upstream 回复 PROXY 头里的客户端地址，健康检查的是 -
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream_addr = upstream.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = upstream.accept().await {
+                tokio::spawn(async move {
+                    let addrs = proxy_protocol::read_header(&mut stream).await?;
+                    let source = addrs.map_or("-".to_owned(), |a| a.source.to_string());
+                    stream.write_all(source.as_bytes()).await?;
+                    Ok::<_, anyhow::Error>(())
+                });
+            }
+        });
+        let path = std::env::temp_dir().join(format!("minginx-pp-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "[limits]\ndeny = [\"198.51.100.0/24\"]\n[[listeners]]\nbind = \"127.0.0.1:0\"\npool = \"p\"\nproxy_protocol = true\n[pools.p]\nupstreams = [\"{upstream_addr}\"]\nproxy_protocol = \"v2\"\n"
+            ),
+        )?;
+        let server = Server::start(&path).await?;
+        let addr = server.local_addr("127.0.0.1:0".parse()?).unwrap();
+
+        let mut client = TcpStream::connect(addr).await?;
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 40000 443\r\n")
+            .await?;
+        let mut source = String::new();
+        client.read_to_string(&mut source).await?;
+        assert_eq!(source, "203.0.113.7:40000");
+
+        // 按 PROXY 头里的地址拒绝，而不是负载均衡的地址
+        let mut client = TcpStream::connect(addr).await?;
+        client
+            .write_all(b"PROXY TCP4 198.51.100.9 10.0.0.1 40000 443\r\n")
+            .await?;
+        assert!(is_rejected(&mut client).await);
+
+        // 没有 PROXY 头的连接被关闭，可能是 RST
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let mut rest = vec![];
+        let closed = client.read_to_end(&mut rest).await;
+        assert!(closed.is_err() || rest.is_empty());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn terminate_and_reencrypt_tls() -> Result<()> {
+        // upstream 用自签名证书，minginx 用默认证书
+        let (cert, key) = self_signed("upstream.local");
+        let upstream_tls = TlsConfig {
+            cert: cert.clone(),
+            key,
+            sni: vec![],
+        };
+        let upstream = echo_server("", Some(tls::acceptor(&upstream_tls, Mode::Tcp)?)).await;
+        let path = std::env::temp_dir().join(format!("minginx-tls-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                r#"
+                [[listeners]]
+                bind = "127.0.0.1:0"
+                pool = "p"
+                tls = {{}}
+                [pools.p]
+                upstreams = ["{upstream}"]
+                tls = {{ ca = "{}", server_name = "upstream.local" }}
+                "#,
+                cert.display()
+            ),
+        )?;
+
+        let server = Server::start(&path).await?;
+        let addr = server.local_addr("127.0.0.1:0".parse()?).unwrap();
+        let client_tls = UpstreamTls {
+            ca: Some(TlsConfig::default().cert),
+            server_name: Some("localhost".into()),
+        };
+        let connector = UpstreamConnector::new(&client_tls, Default::default())?;
+        let mut stream = connector
+            .connect(&addr.to_string(), TcpStream::connect(addr).await?)
+            .await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_reads_rotated_cert() -> Result<()> {
+        let upstream = echo_server("a", None).await;
+        let (cert, key) = self_signed("rotate.local");
+        let path = std::env::temp_dir().join(format!("minginx-rotate-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "[[listeners]]\nbind = \"127.0.0.1:0\"\npool = \"p\"\ntls = {{ cert = \"{}\", key = \"{}\" }}\n[pools.p]\nupstreams = [\"{upstream}\"]\n",
+                cert.display(),
+                key.display()
+            ),
+        )?;
+        let mut server = Server::start(&path).await?;
+        let addr = server.local_addr("127.0.0.1:0".parse()?).unwrap();
+        let connector = |cert: &Path| {
+            let client_tls = UpstreamTls {
+                ca: Some(cert.to_path_buf()),
+                server_name: Some("rotate.local".into()),
+            };
+            UpstreamConnector::new(&client_tls, Default::default())
+        };
+        let connect = |connector: UpstreamConnector| async move {
+            let mut stream = connector
+                .connect(&addr.to_string(), TcpStream::connect(addr).await?)
+                .await?;
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, anyhow::Error>(buf)
+        };
+        // 信任旧证书的客户端
+        let (old, stale) = (connector(&cert)?, connector(&cert)?);
+        assert_eq!(&connect(old).await?, b"a");
+
+        // 配置不变，只换证书文件
+        self_signed("rotate.local");
+        server.reload().await?;
+        assert_eq!(&connect(connector(&cert)?).await?, b"a");
+        assert!(connect(stale).await.is_err());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
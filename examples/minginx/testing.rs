@@ -0,0 +1,42 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tokio_rustls::TlsAcceptor;
+
+use crate::tls::BoxStream;
+
+/// Write a self-signed certificate for `name` and its key to the temp dir.
+pub fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+    let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("minginx-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (
+        dir.join(format!("{name}.pem")),
+        dir.join(format!("{name}.key")),
+    );
+    fs::write(&cert_path, cert.cert.pem()).unwrap();
+    fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+/// Sends `greeting` to every client, then echoes, over TLS with `tls`.
+pub async fn echo_server(greeting: &'static str, tls: Option<TlsAcceptor>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let mut stream: BoxStream = match tls {
+                    Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+                    None => Box::new(stream),
+                };
+                stream.write_all(greeting.as_bytes()).await?;
+                let (mut r, mut w) = tokio::io::split(stream);
+                tokio::io::copy(&mut r, &mut w).await?;
+                Ok::<_, std::io::Error>(())
+            });
+        }
+    });
+    addr
+}
@@ -0,0 +1,248 @@
+use std::{collections::HashMap, fmt, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{self, sign::any_supported_type},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::config::{Mode, Protocol, TlsConfig, UpstreamTls};
+
+/// A client or upstream connection, plain or over TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+/// Picks the certificate by the server name of the client hello.
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()));
+        Some(cert.unwrap_or(&self.default).clone())
+    }
+}
+
+/// TLS acceptor of a listener, `http` listeners offer HTTP/2 and HTTP/1.1 by ALPN.
+pub fn acceptor(config: &TlsConfig, mode: Mode) -> Result<TlsAcceptor> {
+    let by_name = config
+        .sni
+        .iter()
+        .map(|sni| {
+            let key = certified_key(&sni.cert, &sni.key)?;
+            Ok((sni.server_name.to_ascii_lowercase(), key))
+        })
+        .collect::<Result<_>>()?;
+    let resolver = SniResolver {
+        default: certified_key(&config.cert, &config.key)?,
+        by_name,
+    };
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    if mode == Mode::Http {
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Connects to the upstreams of a pool over TLS.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    connector: TlsConnector,
+    config: UpstreamTls,
+}
+
+impl fmt::Debug for UpstreamConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamConnector")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl UpstreamConnector {
+    pub fn new(config: &UpstreamTls, protocol: Protocol) -> Result<Self> {
+        Ok(Self {
+            connector: connector(config, protocol)?,
+            config: config.clone(),
+        })
+    }
+
+    pub async fn connect(&self, addr: &str, stream: TcpStream) -> Result<BoxStream> {
+        let name = server_name(&self.config, addr)?;
+        let stream = self.connector.connect(name, stream).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// TLS connector verifying the upstreams with `ca` or the webpki roots.
+fn connector(config: &UpstreamTls, protocol: Protocol) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let mut client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    // 没有 ALPN 时服务端默认 HTTP/1.1
+    if protocol == Protocol::Http2 {
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+    }
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Name to verify the upstream `addr` with, e.g. `localhost` for `localhost:8443`.
+fn server_name(config: &UpstreamTls, addr: &str) -> Result<ServerName<'static>> {
+    let name = match &config.server_name {
+        Some(name) => name.as_str(),
+        None => addr
+            .rsplit_once(':')
+            .map_or(addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    Ok(ServerName::try_from(name.to_owned())?)
+}
+
+fn certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+    let signing_key = any_supported_type(&key)?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("invalid private key {}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::SniCert,
+        testing::{echo_server, self_signed},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    async fn round_trip(connector: &TlsConnector, addr: &str, name: &str) -> Result<()> {
+        let stream = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from(name.to_owned())?;
+        let mut stream = connector.connect(name, stream).await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn default_cert_and_sni() -> Result<()> {
+        let (a_cert, a_key) = self_signed("a.example.com");
+        let config = TlsConfig {
+            sni: vec![SniCert {
+                server_name: "A.example.com".into(),
+                cert: a_cert.clone(),
+                key: a_key,
+            }],
+            ..Default::default()
+        };
+        let addr = echo_server("", Some(acceptor(&config, Mode::Tcp)?))
+            .await
+            .to_string();
+
+        // 默认证书是 examples/cert.pem，签给 localhost
+        let default_ca = UpstreamTls {
+            ca: Some(config.cert.clone()),
+            server_name: None,
+        };
+        let connector_default = connector(&default_ca, Protocol::Http1)?;
+        round_trip(&connector_default, &addr, "localhost").await?;
+        assert!(round_trip(&connector_default, &addr, "a.example.com")
+            .await
+            .is_err());
+
+        let a_ca = UpstreamTls {
+            ca: Some(a_cert),
+            server_name: None,
+        };
+        let connector_a = connector(&a_ca, Protocol::Http1)?;
+        round_trip(&connector_a, &addr, "a.example.com").await?;
+        assert!(round_trip(&connector_a, &addr, "localhost").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn upstream_server_name() -> Result<()> {
+        let default = UpstreamTls::default();
+        assert_eq!(
+            server_name(&default, "localhost:8443")?,
+            ServerName::try_from("localhost")?
+        );
+        assert_eq!(
+            server_name(&default, "[::1]:8443")?,
+            ServerName::try_from("::1")?
+        );
+        let named = UpstreamTls {
+            ca: None,
+            server_name: Some("api.example.com".into()),
+        };
+        assert_eq!(
+            server_name(&named, "10.0.0.1:443")?,
+            ServerName::try_from("api.example.com")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let config = TlsConfig {
+            cert: "examples/none.pem".into(),
+            ..Default::default()
+        };
+        let e = acceptor(&config, Mode::Tcp).err().unwrap().to_string();
+        assert!(e.contains("examples/none.pem"), "{e}");
+    }
+}
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+};
+
+use crate::{error::ShortenError, AppState};
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// The owner of the api key sent with the request, either as `x-api-key: <key>`
+/// or `Authorization: Bearer <key>`.
+pub struct Owner(pub String);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Owner {
+    type Rejection = ShortenError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let key = api_key(&parts.headers).ok_or(ShortenError::Unauthorized)?;
+
+        state
+            .api_keys
+            .get(key)
+            .map(|owner| Owner(owner.clone()))
+            .ok_or(ShortenError::Unauthorized)
+    }
+}
+
+/// The api key sent with the request, whether it is valid or not.
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    header(API_KEY_HEADER)
+        .or_else(|| header(AUTHORIZATION.as_str())?.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Parse `key1=owner1,key2=owner2` into a map of api key => owner.
+pub fn parse_api_keys(s: &str) -> HashMap<String, String> {
+    s.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, owner)| (key.trim().to_string(), owner.trim().to_string()))
+        .filter(|(key, owner)| !key.is_empty() && !owner.is_empty())
+        .collect()
+}
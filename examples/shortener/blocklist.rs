@@ -0,0 +1,53 @@
+use std::collections::HashSet;
+
+/// Domains which can't be shortened, a blocked domain also blocks its subdomains.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn new<S: AsRef<str>>(domains: impl IntoIterator<Item = S>) -> Self {
+        let domains = domains
+            .into_iter()
+            .map(|d| {
+                d.as_ref()
+                    .trim()
+                    .trim_start_matches('.')
+                    .to_ascii_lowercase()
+            })
+            .filter(|d| !d.is_empty())
+            .collect();
+        Self { domains }
+    }
+
+    pub fn is_blocked(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        // 依次检查 a.b.evil.com、b.evil.com、evil.com、com
+        let mut domain = host.as_str();
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist_matches_subdomains() {
+        let blocklist = Blocklist::new("evil.com, .phishing.org".split(','));
+        assert!(blocklist.is_blocked("evil.com"));
+        assert!(blocklist.is_blocked("www.EVIL.com"));
+        assert!(blocklist.is_blocked("login.phishing.org"));
+        assert!(!blocklist.is_blocked("notevil.com"));
+        assert!(!blocklist.is_blocked("www.rust-lang.org"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    auth::Owner,
+    error::{ErrorBody, ShortenError},
+    extract::Json,
+    store::{LinkOptions, NewUrl},
+    AppState, ShortenReq, MAX_SHORTEN_TRY,
+};
+
+const MAX_BULK_SIZE: usize = 10_000;
+const CSV_CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+/// Result of one url of a bulk request, either `url` or `error` is set.
+#[derive(Serialize, ToSchema)]
+pub struct BulkItem {
+    uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/shortener/bulk",
+    request_body = [ShortenReq],
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "One item per url, in request order", body = [BulkItem]),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+        (status = 413, description = "Too many urls", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody, headers(("retry-after" = u64))),
+    )
+)]
+pub async fn bulk_shorten(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Json(reqs): Json<Vec<ShortenReq>>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let results = state.shorten_many(&reqs, &owner).await?;
+
+    let items: Vec<BulkItem> = reqs
+        .iter()
+        .zip(results)
+        .map(|(req, result)| {
+            let uri = req.uri.clone();
+            match result {
+                Ok(id) => BulkItem {
+                    uri,
+                    url: Some(state.short_url(&id)),
+                    error: None,
+                },
+                Err(e) => BulkItem {
+                    uri,
+                    url: None,
+                    error: Some(e.body()),
+                },
+            }
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+/// Shorten the urls in the first column of a csv body with a header row, and
+/// answer with a `uri,url,error` csv.
+#[utoipa::path(
+    post,
+    path = "/shortener/import",
+    request_body(content = String, content_type = "text/csv"),
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "A `uri,url,error` csv", content_type = "text/csv", body = String),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+        (status = 413, description = "Too many urls", body = ErrorBody),
+        (status = 422, description = "Illegal csv", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody, headers(("retry-after" = u64))),
+    )
+)]
+pub async fn import_csv(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    body: String,
+) -> Result<impl IntoResponse, ShortenError> {
+    let mut reader = csv::Reader::from_reader(body.as_bytes());
+    let mut reqs = vec![];
+    for record in reader.records() {
+        if let Some(uri) = record?.get(0) {
+            reqs.push(ShortenReq {
+                uri: uri.trim().to_string(),
+                options: LinkOptions::default(),
+            });
+        }
+    }
+    let results = state.shorten_many(&reqs, &owner).await?;
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["uri", "url", "error"])?;
+    for (req, result) in reqs.iter().zip(results) {
+        match result {
+            Ok(id) => writer.write_record([req.uri.as_str(), &state.short_url(&id), ""])?,
+            Err(e) => writer.write_record([req.uri.as_str(), "", &e.to_string()])?,
+        }
+    }
+    csv_response(writer)
+}
+
+/// Export the links of the caller as an `id,url,target` csv.
+#[utoipa::path(
+    get,
+    path = "/links/export",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "An `id,url,target` csv", content_type = "text/csv", body = String),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+    )
+)]
+pub async fn export_csv(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+) -> Result<impl IntoResponse, ShortenError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(["id", "url", "target"])?;
+    for record in state.store.list(&owner).await? {
+        writer.write_record([&record.id, &state.short_url(&record.id), &record.url])?;
+    }
+    csv_response(writer)
+}
+
+fn csv_response(writer: csv::Writer<Vec<u8>>) -> Result<Response, ShortenError> {
+    let body = writer.into_inner().map_err(|_| ShortenError::Unknown)?;
+    Ok(([(header::CONTENT_TYPE, CSV_CONTENT_TYPE)], body).into_response())
+}
+
+impl AppState {
+    /// Shorten all `reqs` for `owner`, batching the inserts in one transaction per
+    /// attempt. Items whose id collides are retried with fresh ids.
+    pub async fn shorten_many(
+        &self,
+        reqs: &[ShortenReq],
+        owner: &str,
+    ) -> Result<Vec<Result<String, ShortenError>>, ShortenError> {
+        if reqs.len() > MAX_BULK_SIZE {
+            return Err(ShortenError::BulkTooLarge(MAX_BULK_SIZE));
+        }
+
+        let mut results: Vec<Option<Result<String, ShortenError>>> = reqs
+            .iter()
+            .map(|req| self.check_url(&req.uri).err().map(Err))
+            .collect();
+
+        let mut pending: Vec<usize> = (0..reqs.len()).filter(|&i| results[i].is_none()).collect();
+        for i in 0..MAX_SHORTEN_TRY {
+            if pending.is_empty() {
+                break;
+            }
+            let mut items = Vec::with_capacity(pending.len());
+            for &idx in &pending {
+                let req = &reqs[idx];
+                items.push(NewUrl {
+                    id: self.ids.generate(&*self.store, &req.uri, owner, i).await?,
+                    url: req.uri.clone(),
+                    options: req.options.clone(),
+                });
+            }
+            let ids = self.store.insert_many(&items, owner).await?;
+
+            let mut conflicts = vec![];
+            for (idx, id) in pending.into_iter().zip(ids) {
+                match id {
+                    Some(id) => results[idx] = Some(Ok(id)),
+                    None => conflicts.push(idx),
+                }
+            }
+            if !conflicts.is_empty() {
+                warn!(
+                    "The {} bulk insert encounter {} id conflicts: unique_violation, try again!",
+                    i + 1,
+                    conflicts.len()
+                );
+                self.metrics.shorten_retry(conflicts.len() as u64);
+                self.ids.on_conflict();
+            }
+            pending = conflicts;
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(ShortenError::UrlMaxTrySave)))
+            .collect())
+    }
+}
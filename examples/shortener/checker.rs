@@ -0,0 +1,289 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, StatusCode,
+};
+use tracing::{info, warn};
+use url::{Host, Url};
+
+use crate::{
+    error::ShortenError,
+    target::{self, QueryMerge},
+    AppState,
+};
+
+const CHECK_BATCH: i64 = 100;
+const CHECK_CONCURRENCY: usize = 8;
+/// Redirects followed, like reqwest's default policy.
+const MAX_REDIRECTS: usize = 10;
+
+/// Status recorded when the target can't be reached at all.
+pub const UNREACHABLE: i32 = 0;
+
+/// Periodically sends a `HEAD` to every stored target and records the status,
+/// so dead links can be flagged in the management API.
+pub struct LinkChecker {
+    client: Client,
+    interval: Duration,
+    private: bool,
+    plain_http: bool,
+}
+
+impl LinkChecker {
+    /// Recheck each link every `interval`, giving up on a target after `timeout`.
+    /// Targets on loopback, private and link-local addresses are only checked
+    /// when `private` is set.
+    pub fn new(interval: Duration, timeout: Duration, private: bool) -> Result<Self, ShortenError> {
+        let mut builder = Client::builder()
+            .timeout(timeout)
+            .user_agent("shortener-link-checker");
+        if !private {
+            // 解析域名时过滤掉内网地址，重定向到内网 ip 也拒绝
+            builder =
+                builder
+                    .dns_resolver(Arc::new(PublicResolver))
+                    .redirect(redirect::Policy::custom(|attempt| {
+                        if attempt.previous().len() >= MAX_REDIRECTS {
+                            attempt.error("too many redirects")
+                        } else if is_public_url(attempt.url()) {
+                            attempt.follow()
+                        } else {
+                            attempt.error("redirect to a private address")
+                        }
+                    }));
+        }
+        let client = builder.build().map_err(|_| ShortenError::Unknown)?;
+        Ok(Self {
+            client,
+            interval,
+            private,
+            plain_http: false,
+        })
+    }
+
+    /// Check targets over plain http, for stub servers in tests.
+    #[cfg(test)]
+    pub fn plain_http(mut self) -> Self {
+        self.plain_http = true;
+        self
+    }
+
+    /// HTTP status of `url`, or `UNREACHABLE`. Servers which don't support `HEAD`
+    /// are asked again with `GET`.
+    pub async fn check(&self, url: &str) -> i32 {
+        let public = Url::parse(url).is_ok_and(|url| is_public_url(&url));
+        if !self.private && !public {
+            warn!("Check of {url} refused, the target is a private address");
+            return UNREACHABLE;
+        }
+        let status = match self.client.head(url).send().await {
+            Ok(res)
+                if res.status() == StatusCode::METHOD_NOT_ALLOWED
+                    || res.status() == StatusCode::NOT_IMPLEMENTED =>
+            {
+                self.client.get(url).send().await.map(|res| res.status())
+            }
+            res => res.map(|res| res.status()),
+        };
+        match status {
+            Ok(status) => status.as_u16() as i32,
+            Err(e) => {
+                warn!("Check of {url} failed with error: {e}");
+                UNREACHABLE
+            }
+        }
+    }
+
+    fn target_url(&self, url: &str) -> Option<String> {
+        // 模板链接检查去掉 {path} 之后的地址
+        let url = target::resolve(url, None, None, QueryMerge::Ignore).ok()?;
+        let mut url = Url::parse(&url).ok()?;
+        if self.plain_http {
+            url.set_scheme("http").ok()?;
+        }
+        Some(url.into())
+    }
+
+    /// Check every link which is due, return how many were checked.
+    pub async fn run_once(&self, state: &AppState) -> Result<usize, ShortenError> {
+        let before = Utc::now() - self.interval;
+        let mut checked = 0;
+        loop {
+            let records = state.store.due_for_check(before, CHECK_BATCH).await?;
+            if records.is_empty() {
+                break;
+            }
+            let count = records.len();
+            let results: Vec<_> = stream::iter(records)
+                .map(|record| async move {
+                    let status = match self.target_url(&record.url) {
+                        Some(url) => self.check(&url).await,
+                        None => UNREACHABLE,
+                    };
+                    (record.id, status)
+                })
+                .buffer_unordered(CHECK_CONCURRENCY)
+                .collect()
+                .await;
+            for (id, status) in results {
+                state.store.record_check(&id, status, Utc::now()).await?;
+                state.metrics.link_checked(status);
+            }
+            checked += count;
+            if count < CHECK_BATCH as usize {
+                break;
+            }
+        }
+        Ok(checked)
+    }
+
+    pub async fn run(self, state: Arc<AppState>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            match self.run_once(&state).await {
+                Ok(0) => {}
+                Ok(n) => info!("Checked the targets of {n} links"),
+                Err(e) => warn!("Link check failed with error: {e}"),
+            }
+        }
+    }
+}
+
+/// Resolves only to the public addresses of a name.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether the host of `url` isn't an ip literal of a private address, domains
+/// are left to `PublicResolver`.
+fn is_public_url(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Ipv4(ip)) => is_public(ip.into()),
+        Some(Host::Ipv6(ip)) => is_public(ip.into()),
+        Some(Host::Domain(_)) => true,
+        None => false,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 是运营商级 NAT
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+/// A local server answering like a few kinds of targets, for tests:
+/// `/ok` 200, `/gone` 404, `/get-only` 405 to `HEAD` and `/slow` after 2 seconds.
+#[cfg(test)]
+pub async fn stub_server() -> std::net::SocketAddr {
+    use axum::{http::StatusCode, routing::get, Router};
+
+    let app = Router::new()
+        .route("/ok", get(|| async { "ok" }))
+        .route("/gone", get(|| async { StatusCode::NOT_FOUND }))
+        .route(
+            "/get-only",
+            get(|| async { "ok" }).head(|| async { StatusCode::METHOD_NOT_ALLOWED }),
+        )
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                "ok"
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_statuses() -> anyhow::Result<()> {
+        let addr = stub_server().await;
+        let checker = LinkChecker::new(Duration::from_secs(60), Duration::from_millis(500), true)?;
+
+        assert_eq!(checker.check(&format!("http://{addr}/ok")).await, 200);
+        assert_eq!(checker.check(&format!("http://{addr}/gone")).await, 404);
+        assert_eq!(checker.check(&format!("http://{addr}/get-only")).await, 200);
+        assert_eq!(
+            checker.check(&format!("http://{addr}/slow")).await,
+            UNREACHABLE
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_targets_are_refused() -> anyhow::Result<()> {
+        let addr = stub_server().await;
+        let checker = LinkChecker::new(Duration::from_secs(60), Duration::from_millis(500), false)?;
+        for url in [
+            format!("http://{addr}/ok"),
+            format!("http://localhost:{}/ok", addr.port()),
+            "http://169.254.169.254/latest/meta-data/".to_string(),
+            "http://10.0.0.1/".to_string(),
+            "http://[::1]/".to_string(),
+            "http://[::ffff:127.0.0.1]/".to_string(),
+        ] {
+            assert_eq!(checker.check(&url).await, UNREACHABLE, "{url}");
+        }
+        assert!(is_public("93.184.216.34".parse()?));
+        assert!(!is_public("100.64.0.1".parse()?));
+        assert!(!is_public("fe80::1".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn plain_http_only_changes_the_scheme() -> anyhow::Result<()> {
+        let checker = LinkChecker::new(Duration::from_secs(60), Duration::from_secs(1), true)?;
+        assert_eq!(
+            checker.target_url("example.com/https/a").as_deref(),
+            Some("https://example.com/https/a")
+        );
+        let checker = checker.plain_http();
+        assert_eq!(
+            checker.target_url("https.example.com/https/a").as_deref(),
+            Some("http://https.example.com/https/a")
+        );
+        Ok(())
+    }
+}
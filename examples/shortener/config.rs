@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+use crate::{
+    auth,
+    id::{IdStrategy, ID_LEN, MAX_ID_LEN},
+    limit::Quota,
+};
+
+const DEFAULT_CONFIG: &str = "shortener.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("can't read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("invalid config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+
+    #[error("store_url is required, set it in the config file, STORE_URL or --store-url")]
+    StoreUrlMissing,
+
+    #[error("base_url {0} must be an absolute http or https url")]
+    BaseUrlIllegal(String),
+
+    #[error("pool_size must be at least 1")]
+    PoolSizeIllegal,
+
+    #[error("id_length {0} must be between 1 and {MAX_ID_LEN}")]
+    IdLengthIllegal(usize),
+
+    #[error("link_check_timeout must be at least 1 second")]
+    LinkCheckTimeoutIllegal,
+
+    #[error("invalid log_level {0}: {1}")]
+    LogLevelIllegal(String, String),
+
+    #[error("HOST {0} must be an address like 127.0.0.1:3000")]
+    HostIllegal(String),
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "URL shortener")]
+pub struct Cli {
+    /// TOML config file, `shortener.toml` is read if it exists.
+    #[arg(short, long, env = "SHORTENER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Subcommand)]
+pub enum Command {
+    /// Serve the shortener, the default.
+    Serve,
+    /// Apply the database migrations and exit.
+    Migrate,
+}
+
+/// Settings given by environment variables or command line flags, flags win.
+#[derive(Debug, Clone, Default, Args)]
+pub struct Overrides {
+    /// Address to listen on, e.g. `0.0.0.0:3000`.
+    #[arg(long, env = "BIND")]
+    pub bind: Option<SocketAddr>,
+    /// Public url the short links are built on, e.g. `https://s.example.com`.
+    #[arg(long, env = "BASE_URL")]
+    pub base_url: Option<String>,
+    /// `postgres://`, `sqlite://` or `memory://`.
+    #[arg(long, env = "STORE_URL")]
+    pub store_url: Option<String>,
+    /// Maximum number of database connections.
+    #[arg(long, env = "POOL_SIZE")]
+    pub pool_size: Option<u32>,
+    /// `nanoid`, `sequence` or `hash`.
+    #[arg(long, env = "ID_STRATEGY")]
+    pub id_strategy: Option<IdStrategy>,
+    /// Length of new ids.
+    #[arg(long, env = "ID_LENGTH")]
+    pub id_length: Option<usize>,
+    /// `info`, `debug`, or a filter like `info,sqlx=warn`.
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// `key1=owner1,key2=owner2`.
+    #[arg(long, env = "API_KEYS")]
+    pub api_keys: Option<String>,
+    /// `evil.com,phishing.org`.
+    #[arg(long, env = "BLOCKED_DOMAINS")]
+    pub blocked_domains: Option<String>,
+    /// `burst/seconds` per client ip.
+    #[arg(long, env = "RATE_LIMIT_IP")]
+    pub rate_limit_ip: Option<Quota>,
+    /// `burst/seconds` per api key.
+    #[arg(long, env = "RATE_LIMIT_KEY")]
+    pub rate_limit_key: Option<Quota>,
+    /// Seconds between two checks of a target, 0 disables the checker.
+    #[arg(long, env = "LINK_CHECK_INTERVAL")]
+    pub link_check_interval: Option<u64>,
+    /// Seconds to wait for a target to answer a check.
+    #[arg(long, env = "LINK_CHECK_TIMEOUT")]
+    pub link_check_timeout: Option<u64>,
+    /// `true` to also check targets on loopback, private and link-local addresses.
+    #[arg(long, env = "LINK_CHECK_PRIVATE")]
+    pub link_check_private: Option<bool>,
+    /// Seconds `/readyz` fails before shutting down.
+    #[arg(long, env = "DRAIN_PERIOD")]
+    pub drain_period: Option<u64>,
+}
+
+/// Typed settings of the shortener: defaults, then the config file, then
+/// environment variables, then command line flags.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// Defaults to `http://{bind}`.
+    pub base_url: Option<String>,
+    pub store_url: Option<String>,
+    pub pool_size: u32,
+    pub id_strategy: IdStrategy,
+    pub id_length: usize,
+    pub log_level: String,
+    pub api_keys: HashMap<String, String>, // api key => owner
+    pub blocked_domains: Vec<String>,
+    pub rate_limit_ip: Quota,
+    pub rate_limit_key: Quota,
+    /// Seconds, 0 disables the link checker.
+    pub link_check_interval: u64,
+    pub link_check_timeout: u64,
+    /// Check targets on loopback, private and link-local addresses too. Off by
+    /// default, otherwise `check_status` tells users about the internal network.
+    pub link_check_private: bool,
+    /// Seconds `/readyz` fails while still serving after ctrl-c or SIGTERM, for
+    /// the load balancer to stop sending traffic.
+    pub drain_period: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            base_url: None,
+            store_url: None,
+            pool_size: 10,
+            id_strategy: IdStrategy::default(),
+            id_length: ID_LEN,
+            log_level: "info".to_string(),
+            api_keys: HashMap::new(),
+            blocked_domains: vec![],
+            rate_limit_ip: Quota::new(20, Duration::from_secs(60)),
+            rate_limit_key: Quota::new(600, Duration::from_secs(60)),
+            link_check_interval: 3600,
+            link_check_timeout: 5,
+            link_check_private: false,
+            drain_period: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file of `cli`, apply its overrides and validate the result.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG).exists() => Self::from_file(DEFAULT_CONFIG)?,
+            None => Self::default(),
+        };
+        config.apply(&cli.overrides);
+        // 兼容旧的 HOST 和 PG_URL 环境变量
+        if cli.overrides.bind.is_none() {
+            if let Ok(host) = env::var("HOST") {
+                config.bind = legacy_bind(&host)?;
+            }
+        }
+        if config.store_url.is_none() {
+            config.store_url = env::var("PG_URL").ok();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&s).map_err(|e| ConfigError::Parse(path.into(), e))
+    }
+
+    pub fn apply(&mut self, overrides: &Overrides) {
+        let o = overrides.clone();
+        if let Some(bind) = o.bind {
+            self.bind = bind;
+        }
+        if o.base_url.is_some() {
+            self.base_url = o.base_url;
+        }
+        if o.store_url.is_some() {
+            self.store_url = o.store_url;
+        }
+        if let Some(pool_size) = o.pool_size {
+            self.pool_size = pool_size;
+        }
+        if let Some(id_strategy) = o.id_strategy {
+            self.id_strategy = id_strategy;
+        }
+        if let Some(id_length) = o.id_length {
+            self.id_length = id_length;
+        }
+        if let Some(log_level) = o.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(api_keys) = o.api_keys {
+            self.api_keys = auth::parse_api_keys(&api_keys);
+        }
+        if let Some(domains) = o.blocked_domains {
+            self.blocked_domains = domains.split(',').map(|d| d.trim().to_string()).collect();
+        }
+        if let Some(quota) = o.rate_limit_ip {
+            self.rate_limit_ip = quota;
+        }
+        if let Some(quota) = o.rate_limit_key {
+            self.rate_limit_key = quota;
+        }
+        if let Some(secs) = o.link_check_interval {
+            self.link_check_interval = secs;
+        }
+        if let Some(secs) = o.link_check_timeout {
+            self.link_check_timeout = secs;
+        }
+        if let Some(private) = o.link_check_private {
+            self.link_check_private = private;
+        }
+        if let Some(secs) = o.drain_period {
+            self.drain_period = secs;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.store_url.is_none() {
+            return Err(ConfigError::StoreUrlMissing);
+        }
+        if let Some(base_url) = &self.base_url {
+            match Url::parse(base_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+                _ => return Err(ConfigError::BaseUrlIllegal(base_url.clone())),
+            }
+        }
+        if self.pool_size == 0 {
+            return Err(ConfigError::PoolSizeIllegal);
+        }
+        if !(1..=MAX_ID_LEN).contains(&self.id_length) {
+            return Err(ConfigError::IdLengthIllegal(self.id_length));
+        }
+        if self.link_check_timeout == 0 {
+            return Err(ConfigError::LinkCheckTimeoutIllegal);
+        }
+        self.log_filter()?;
+        Ok(())
+    }
+
+    pub fn store_url(&self) -> &str {
+        self.store_url.as_deref().unwrap_or_default()
+    }
+
+    /// Public url of the short links, without a trailing `/`.
+    pub fn base_url(&self) -> String {
+        match &self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("http://{}", self.bind),
+        }
+    }
+
+    pub fn log_filter(&self) -> Result<EnvFilter, ConfigError> {
+        EnvFilter::try_new(&self.log_level)
+            .map_err(|e| ConfigError::LogLevelIllegal(self.log_level.clone(), e.to_string()))
+    }
+}
+
+/// Bind address of the legacy `HOST` environment variable.
+fn legacy_bind(host: &str) -> Result<SocketAddr, ConfigError> {
+    host.parse()
+        .map_err(|_| ConfigError::HostIllegal(host.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_then_overrides() -> anyhow::Result<()> {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind = "0.0.0.0:8080"
+            base_url = "https://s.example.com/"
+            store_url = "memory://"
+            id_strategy = "hash"
+            id_length = 8
+            rate_limit_ip = "5/10"
+            blocked_domains = ["evil.com"]
+
+            [api_keys]
+            dev-key = "kindy"
+            "#,
+        )?;
+        assert_eq!(config.bind, "0.0.0.0:8080".parse()?);
+        assert_eq!(config.base_url(), "https://s.example.com");
+        assert_eq!(config.id_strategy, IdStrategy::Hash);
+        assert_eq!(config.rate_limit_ip, Quota::new(5, Duration::from_secs(10)));
+        assert_eq!(config.api_keys["dev-key"], "kindy");
+        // 未写的字段取默认值
+        assert_eq!(config.pool_size, 10);
+        config.validate()?;
+
+        let cli = Cli::try_parse_from([
+            "shortener",
+            "--id-length",
+            "10",
+            "--api-keys",
+            "a=alice",
+            "migrate",
+        ])?;
+        assert_eq!(cli.command, Some(Command::Migrate));
+        config.apply(&cli.overrides);
+        assert_eq!(config.id_length, 10);
+        assert_eq!(config.id_strategy, IdStrategy::Hash);
+        assert_eq!(config.api_keys.len(), 1);
+        assert_eq!(config.api_keys["a"], "alice");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let valid = Config {
+            store_url: Some("memory://".to_string()),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.base_url(), "http://127.0.0.1:3000");
+
+        let cases = [
+            (
+                Config {
+                    store_url: None,
+                    ..valid.clone()
+                },
+                "store_url is required",
+            ),
+            (
+                Config {
+                    base_url: Some("s.example.com".to_string()),
+                    ..valid.clone()
+                },
+                "base_url",
+            ),
+            (
+                Config {
+                    pool_size: 0,
+                    ..valid.clone()
+                },
+                "pool_size",
+            ),
+            (
+                Config {
+                    id_length: 22,
+                    ..valid.clone()
+                },
+                "id_length 22",
+            ),
+            (
+                Config {
+                    link_check_timeout: 0,
+                    ..valid.clone()
+                },
+                "link_check_timeout",
+            ),
+            (
+                Config {
+                    log_level: "info,=[".to_string(),
+                    ..valid.clone()
+                },
+                "log_level",
+            ),
+        ];
+        for (config, msg) in cases {
+            let e = config.validate().unwrap_err().to_string();
+            assert!(e.contains(msg), "{e} should contain {msg}");
+        }
+
+        assert!(toml::from_str::<Config>("bind = \"nowhere\"").is_err());
+        assert_eq!(
+            legacy_bind("0.0.0.0:8080").unwrap(),
+            "0.0.0.0:8080".parse().unwrap()
+        );
+        let e = legacy_bind("localhost").unwrap_err().to_string();
+        assert!(e.contains("HOST localhost"), "{e}");
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{header, StatusCode};
+use serde::Serialize;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+#[derive(Error, Debug)]
+pub enum ShortenError {
+    #[error("the id must be 1 to {max} letters, digits, '_' or '-'", max = crate::id::MAX_ID_LEN)]
+    IdIllegal,
+
+    #[error("the id {0} can't not found")]
+    IdNotFound(String),
+
+    #[error("the id {0} is already taken")]
+    IdConflict(String),
+
+    #[error("the url {0} is already shortened")]
+    UrlExists(String),
+
+    #[error("the domain {0} is blocked")]
+    DomainBlocked(String),
+
+    #[error("too many requests, retry after {0} seconds")]
+    TooManyRequests(u64),
+
+    #[error("missing or invalid api key")]
+    Unauthorized,
+
+    #[error("url parse error")]
+    UrlIllegal(#[from] url::ParseError),
+
+    #[error("csv parse error")]
+    CsvIllegal(#[from] csv::Error),
+
+    #[error("at most {0} urls can be shortened at once")]
+    BulkTooLarge(usize),
+
+    #[error("the qr code size {0} must be between {min} and {max}", min = crate::qr::MIN_QR_SIZE, max = crate::qr::MAX_QR_SIZE)]
+    QrSizeIllegal(u32),
+
+    #[error("invalid json body: {0}")]
+    JsonIllegal(#[from] JsonRejection),
+
+    #[error("invalid query string: {0}")]
+    QueryIllegal(#[from] QueryRejection),
+
+    #[error("no route for {0}")]
+    RouteNotFound(String),
+
+    #[error("the service is not ready: {0}")]
+    NotReady(String),
+
+    #[error("the maximum number of attempts is reached")]
+    UrlMaxTrySave,
+
+    #[error("database error")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("the database schema is at version {0}, newer than the latest known version {1}")]
+    SchemaTooNew(i64, i64),
+
+    #[error("database migration error")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("unsupported store url {0}")]
+    StoreUnsupported(String),
+
+    #[error("unknown error")]
+    Unknown,
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable machine readable error code, one of `id_illegal`, `id_not_found`,
+    /// `url_exists`, `domain_blocked`, `too_many_requests`, `unauthorized`,
+    /// `url_illegal`, `csv_illegal`, `bulk_too_large`, `qr_size_illegal`,
+    /// `json_illegal`, `query_illegal`, `route_not_found`, `not_ready`,
+    /// `max_try_reached` and `internal_error`.
+    #[schema(example = "id_not_found")]
+    pub code: String,
+    /// Human readable description, may change between releases.
+    #[schema(example = "the id X_2C4H can't not found")]
+    pub message: String,
+}
+
+impl ShortenError {
+    /// Stable machine readable code of the error, documented in `/openapi.json`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ShortenError::IdIllegal => "id_illegal",
+            ShortenError::IdNotFound(_) => "id_not_found",
+            ShortenError::UrlExists(_) => "url_exists",
+            ShortenError::DomainBlocked(_) => "domain_blocked",
+            ShortenError::TooManyRequests(_) => "too_many_requests",
+            ShortenError::Unauthorized => "unauthorized",
+            ShortenError::UrlIllegal(_) => "url_illegal",
+            ShortenError::CsvIllegal(_) => "csv_illegal",
+            ShortenError::BulkTooLarge(_) => "bulk_too_large",
+            ShortenError::QrSizeIllegal(_) => "qr_size_illegal",
+            ShortenError::JsonIllegal(_) => "json_illegal",
+            ShortenError::QueryIllegal(_) => "query_illegal",
+            ShortenError::RouteNotFound(_) => "route_not_found",
+            ShortenError::NotReady(_) => "not_ready",
+            ShortenError::UrlMaxTrySave => "max_try_reached",
+            ShortenError::IdConflict(_)
+            | ShortenError::StoreUnsupported(_)
+            | ShortenError::SchemaTooNew(..)
+            | ShortenError::MigrateError(_)
+            | ShortenError::Unknown
+            | ShortenError::DatabaseError(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ShortenError::IdIllegal
+            | ShortenError::UrlMaxTrySave
+            | ShortenError::UrlIllegal(_)
+            | ShortenError::CsvIllegal(_)
+            | ShortenError::QrSizeIllegal(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ShortenError::JsonIllegal(e) => e.status(),
+            ShortenError::QueryIllegal(e) => e.status(),
+            ShortenError::BulkTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ShortenError::IdNotFound(_) | ShortenError::RouteNotFound(_) => StatusCode::NOT_FOUND,
+            ShortenError::UrlExists(_) => StatusCode::CONFLICT,
+            ShortenError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ShortenError::DomainBlocked(_) => StatusCode::FORBIDDEN,
+            ShortenError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ShortenError::NotReady(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ShortenError::IdConflict(_)
+            | ShortenError::StoreUnsupported(_)
+            | ShortenError::SchemaTooNew(..)
+            | ShortenError::MigrateError(_)
+            | ShortenError::Unknown
+            | ShortenError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let message = match self.status() {
+            StatusCode::INTERNAL_SERVER_ERROR => "server error, try again later".to_string(),
+            _ => self.to_string(),
+        };
+        ErrorBody {
+            code: self.code().to_string(),
+            message,
+        }
+    }
+}
+
+impl IntoResponse for ShortenError {
+    fn into_response(self) -> Response {
+        let body = Json(self.body());
+        match self {
+            ShortenError::TooManyRequests(secs) => {
+                let headers = [(header::RETRY_AFTER, secs.to_string())];
+                (self.status(), headers, body).into_response()
+            }
+            _ => (self.status(), body).into_response(),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use axum::{
+    extract::{FromRequest, FromRequestParts},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::error::ShortenError;
+
+/// `axum::Json` whose rejections are turned into json `ShortenError` bodies.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ShortenError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// `axum::extract::Query` whose rejections are turned into json `ShortenError` bodies.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ShortenError))]
+pub struct Query<T>(pub T);
@@ -0,0 +1,63 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use axum::extract::State;
+use serde_json::{json, Value};
+use tokio::signal;
+use tracing::info;
+
+use crate::{error::ShortenError, extract::Json, AppState};
+
+/// Liveness probe, the process is up and serving requests.
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness probe, fails while shutting down or when the store can't run a query.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> Result<Json<Value>, ShortenError> {
+    if state.draining.load(Ordering::Relaxed) {
+        return Err(ShortenError::NotReady("shutting down".to_string()));
+    }
+    state
+        .store
+        .ping()
+        .await
+        .map_err(|e| ShortenError::NotReady(e.to_string()))?;
+    Ok(Json(json!({ "status": "ok" })))
+}
+
+/// Resolve on ctrl-c or SIGTERM once `drain_period` has passed, see `drain`.
+pub async fn shutdown_signal(state: Arc<AppState>, drain_period: Duration) {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("无法监听 ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("无法监听 SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    drain(&state, drain_period).await;
+}
+
+/// Stop reporting ready but keep accepting for `period`, so load balancers take
+/// this instance out before it stops listening.
+pub async fn drain(state: &AppState, period: Duration) {
+    state.draining.store(true, Ordering::Relaxed);
+    info!(
+        "URL shortener not ready, draining for {}s",
+        period.as_secs()
+    );
+    tokio::time::sleep(period).await;
+    info!("URL shortener shutting down, waiting for in-flight requests");
+}
@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use strum::{Display, EnumString};
+
+use crate::{error::ShortenError, store::UrlStore};
+
+pub const ID_LEN: usize = 6; // 6位大小写字母+数字已经形成足够大的取值空间
+pub const MAX_ID_LEN: usize = 21; // nanoid 的默认长度，也是 urls.id 列能存下的上限
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// How new short ids are picked, selected per deployment with `id_strategy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, EnumString, Display, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    /// Random nanoid, one character longer each time a conflict is met.
+    #[default]
+    Nanoid,
+    /// Base62 of the next value of the store's sequence, conflicts only with ids
+    /// made by another strategy, retried like the others.
+    Sequence,
+    /// Base62 of the sha256 of owner and url, the same url always gets the same id.
+    Hash,
+}
+
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    len: AtomicUsize,
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        Self::new(IdStrategy::default(), ID_LEN)
+    }
+}
+
+impl IdGenerator {
+    pub fn new(strategy: IdStrategy, len: usize) -> Self {
+        Self {
+            strategy,
+            len: AtomicUsize::new(len.clamp(1, MAX_ID_LEN)),
+        }
+    }
+
+    /// The id to try for `url` of `owner`, `attempt` counts the conflicts met so far.
+    pub async fn generate(
+        &self,
+        store: &dyn UrlStore,
+        url: &str,
+        owner: &str,
+        attempt: u8,
+    ) -> Result<String, ShortenError> {
+        let len = self.len.load(Ordering::Relaxed);
+        let id = match self.strategy {
+            IdStrategy::Nanoid => nanoid::nanoid!(len),
+            IdStrategy::Sequence => base62(store.next_sequence().await?),
+            IdStrategy::Hash => hash_id(url, owner, attempt, len),
+        };
+        Ok(id)
+    }
+
+    /// Called when a generated id was already taken: nanoid ids grow by one
+    /// character so the keyspace keeps up with the number of stored urls.
+    pub fn on_conflict(&self) {
+        if self.strategy == IdStrategy::Nanoid {
+            let _ = self
+                .len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                    (len < MAX_ID_LEN).then_some(len + 1)
+                });
+        }
+    }
+}
+
+/// Whether `id` could have been generated by any of the strategies.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+}
+
+fn base62(mut n: u64) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).expect("base62 digits are ascii")
+}
+
+fn hash_id(url: &str, owner: &str, attempt: u8, len: usize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(owner.as_bytes());
+    hasher.update([0]);
+    hasher.update(url.as_bytes());
+    // 冲突时加盐重新计算
+    if attempt > 0 {
+        hasher.update([attempt]);
+    }
+    let digest = hasher.finalize();
+
+    digest
+        .chunks(8)
+        .map(|chunk| base62(u64::from_be_bytes(chunk.try_into().expect("8 bytes chunk"))))
+        .collect::<String>()
+        .chars()
+        .take(len)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base62_works() {
+        assert_eq!(base62(0), "0");
+        assert_eq!(base62(61), "z");
+        assert_eq!(base62(62), "10");
+        assert_eq!(base62(u64::MAX), "LygHa16AHYF");
+    }
+
+    #[test]
+    fn hash_id_is_stable() {
+        let id = hash_id("www.rust-lang.org", "alice", 0, ID_LEN);
+        assert_eq!(id.len(), ID_LEN);
+        assert_eq!(id, hash_id("www.rust-lang.org", "alice", 0, ID_LEN));
+        assert_ne!(id, hash_id("www.rust-lang.org", "alice", 1, ID_LEN));
+        assert_ne!(id, hash_id("www.rust-lang.org", "bob", 0, ID_LEN));
+        assert!(is_valid_id(&id));
+    }
+
+    #[test]
+    fn nanoid_grows_on_conflict() {
+        let ids = IdGenerator::new(IdStrategy::Nanoid, MAX_ID_LEN - 1);
+        ids.on_conflict();
+        ids.on_conflict();
+        assert_eq!(ids.len.load(Ordering::Relaxed), MAX_ID_LEN);
+    }
+}
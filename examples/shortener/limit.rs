@@ -0,0 +1,171 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+use serde::Deserialize;
+
+use crate::{auth, error::ShortenError, AppState};
+
+/// `burst` requests, refilled evenly over `per`. Written as `burst/seconds`, e.g. `20/60`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Quota {
+    burst: u32,
+    per: Duration,
+}
+
+impl Quota {
+    pub fn new(burst: u32, per: Duration) -> Self {
+        Self {
+            burst: burst.max(1),
+            per,
+        }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.burst as f64 / self.per.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, secs) = s
+            .split_once('/')
+            .ok_or_else(|| format!("quota {s} must be written as burst/seconds"))?;
+        let burst = burst.trim().parse().map_err(|e| format!("{e}"))?;
+        let secs = secs.trim().parse().map_err(|e| format!("{e}"))?;
+        Ok(Self::new(burst, Duration::from_secs(secs)))
+    }
+}
+
+impl TryFrom<String> for Quota {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client ip and api key.
+pub struct RateLimiter {
+    per_ip: Quota,
+    per_key: Quota,
+    buckets: DashMap<String, Bucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(
+            Quota::new(20, Duration::from_secs(60)),
+            Quota::new(600, Duration::from_secs(60)),
+        )
+    }
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Quota, per_key: Quota) -> Self {
+        Self {
+            per_ip,
+            per_key,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token from the bucket of `key`, or return how long to wait for one.
+    fn acquire(&self, key: String, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: quota.burst as f64,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * quota.refill_per_sec()).min(quota.burst as f64);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / quota.refill_per_sec();
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+
+    /// Drop the buckets which are full again, so the map doesn't grow forever.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|key, bucket| {
+            let quota = if key.starts_with("ip:") {
+                self.per_ip
+            } else {
+                self.per_key
+            };
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * quota.refill_per_sec() < quota.burst as f64
+        });
+    }
+}
+
+/// Middleware limiting the requests per client ip and per api key.
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ShortenError> {
+    let limiter = &state.limiter;
+    let too_many = |wait: Duration| ShortenError::TooManyRequests(wait.as_secs_f64().ceil() as u64);
+
+    if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        limiter
+            .acquire(format!("ip:{}", addr.ip()), limiter.per_ip)
+            .map_err(too_many)?;
+    }
+    if let Some(key) = auth::api_key(req.headers()) {
+        limiter
+            .acquire(format!("key:{key}"), limiter.per_key)
+            .map_err(too_many)?;
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_works() {
+        let limiter = RateLimiter::default();
+        let quota = Quota::new(2, Duration::from_secs(60));
+
+        assert!(limiter.acquire("ip:a".into(), quota).is_ok());
+        assert!(limiter.acquire("ip:a".into(), quota).is_ok());
+        let wait = limiter.acquire("ip:a".into(), quota).unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        // 不同的 key 互不影响
+        assert!(limiter.acquire("ip:b".into(), quota).is_ok());
+    }
+
+    #[test]
+    fn quota_parse() {
+        let quota: Quota = "20/60".parse().unwrap();
+        assert_eq!(quota, Quota::new(20, Duration::from_secs(60)));
+        assert!("20".parse::<Quota>().is_err());
+    }
+}
@@ -0,0 +1,1180 @@
+mod auth;
+mod blocklist;
+mod bulk;
+mod checker;
+mod config;
+mod error;
+mod extract;
+mod health;
+mod id;
+mod limit;
+mod metrics;
+mod openapi;
+mod preview;
+mod qr;
+mod store;
+mod target;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::Uri,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    Router,
+};
+use clap::Parser;
+use derive_builder::Builder;
+use dotenv::dotenv;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+use auth::Owner;
+use blocklist::Blocklist;
+use checker::LinkChecker;
+use config::{Cli, Command, Config};
+use error::ShortenError;
+use extract::{Json, Query};
+use id::IdGenerator;
+use limit::RateLimiter;
+use metrics::Metrics;
+use store::{LinkOptions, LinkUpdate, NewUrl, UrlRecord, UrlStore};
+use target::{QueryMerge, RedirectType};
+
+const MAX_SHORTEN_TRY: u8 = 3;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+
+    let layer = tracing_subscriber::fmt::Layer::new().with_filter(config.log_filter()?);
+    tracing_subscriber::registry().with(layer).init();
+
+    let store = store::connect(config.store_url(), config.pool_size).await?;
+    let version = store.migrate().await?;
+    if cli.command == Some(Command::Migrate) {
+        info!("URL shortener database migrated to version {version}");
+        return Ok(());
+    }
+    info!("URL shortener database at version {version}");
+
+    let shared_state = Arc::new(
+        AppStateBuilder::default()
+            .store(store)
+            .base_url(config.base_url())
+            .api_keys(config.api_keys.clone())
+            .ids(IdGenerator::new(config.id_strategy, config.id_length))
+            .blocklist(Blocklist::new(&config.blocked_domains))
+            .limiter(RateLimiter::new(
+                config.rate_limit_ip,
+                config.rate_limit_key,
+            ))
+            .build()?,
+    );
+    let app = app(shared_state.clone());
+
+    // 定期清理已经回满的令牌桶
+    let state = shared_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            state.limiter.sweep();
+        }
+    });
+
+    // 定期检查目标链接是否还能访问
+    if config.link_check_interval > 0 {
+        let checker = LinkChecker::new(
+            Duration::from_secs(config.link_check_interval),
+            Duration::from_secs(config.link_check_timeout),
+            config.link_check_private,
+        )?;
+        tokio::spawn(checker.run(shared_state.clone()));
+    }
+
+    let listener = TcpListener::bind(config.bind).await?;
+    info!(
+        "URL shortener serve in {}, links at {}",
+        config.bind, shared_state.base_url
+    );
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(health::shutdown_signal(
+        shared_state,
+        Duration::from_secs(config.drain_period),
+    ))
+    .await?;
+    info!("URL shortener exit");
+    Ok(())
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    // 只对创建链接的接口限流
+    let create = Router::new()
+        .route("/shortener", post(shorten))
+        .route("/shortener/bulk", post(bulk::bulk_shorten))
+        .route("/shortener/import", post(bulk::import_csv))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit::rate_limit,
+        ));
+
+    Router::new()
+        .route("/:id", get(redirect))
+        .route("/:id/*path", get(redirect_path))
+        .merge(create)
+        .route("/qr/:id", get(qr::qr_code))
+        .route("/links", get(list_links))
+        .route("/links/export", get(bulk::export_csv))
+        .route("/links/:id", patch(update_link).delete(delete_link))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .fallback(not_found)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ))
+        .with_state(state)
+}
+
+async fn not_found(uri: Uri) -> ShortenError {
+    ShortenError::RouteNotFound(uri.path().to_string())
+}
+
+#[derive(Deserialize, IntoParams)]
+struct RedirectParams {
+    /// `1` or `true` to show the preview page instead of redirecting.
+    preview: Option<String>,
+}
+
+/// Redirect to the target of `id`, or show the preview page for `/:id+`,
+/// `/:id?preview=1` and links created with `preview`. The query string is
+/// forwarded according to the link's `query_merge`.
+#[utoipa::path(
+    get,
+    path = "/{id}",
+    params(("id" = String, Path, description = "Short id, append `+` for the preview page"), RedirectParams),
+    responses(
+        (status = 303, description = "Redirect to the target with the link's `redirect_type`, 301, 302, 303, 307 or 308", headers(("location" = String))),
+        (status = 200, description = "Preview page", content_type = "text/html", body = String),
+        (status = 422, description = "Illegal id", body = ErrorBody),
+        (status = 404, description = "Unknown id", body = ErrorBody),
+    )
+)]
+async fn redirect(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<RedirectParams>,
+    uri: Uri,
+) -> Result<impl IntoResponse, ShortenError> {
+    visit(&state, id, None, uri.query(), &params).await
+}
+
+/// Redirect to a templated target, `{path}` in the target is replaced by `path`.
+#[utoipa::path(
+    get,
+    path = "/{id}/{path}",
+    params(
+        ("id" = String, Path, description = "Short id of a templated target"),
+        ("path" = String, Path, description = "Replaces `{path}` in the target"),
+        RedirectParams,
+    ),
+    responses(
+        (status = 303, description = "Redirect to the target with the link's `redirect_type`, 301, 302, 303, 307 or 308", headers(("location" = String))),
+        (status = 200, description = "Preview page", content_type = "text/html", body = String),
+        (status = 422, description = "Illegal id", body = ErrorBody),
+        (status = 404, description = "Unknown id, or the target isn't templated", body = ErrorBody),
+    )
+)]
+async fn redirect_path(
+    State(state): State<Arc<AppState>>,
+    Path((id, _)): Path<(String, String)>,
+    Query(params): Query<RedirectParams>,
+    uri: Uri,
+) -> Result<impl IntoResponse, ShortenError> {
+    // 使用未解码的原始路径，避免 %2F、%3F 解码后改变目标的结构
+    let path = uri
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    visit(&state, id, Some(path), uri.query(), &params).await
+}
+
+async fn visit(
+    state: &AppState,
+    id: String,
+    path: Option<&str>,
+    query: Option<&str>,
+    params: &RedirectParams,
+) -> Result<Response, ShortenError> {
+    let (id, plus) = match id.strip_suffix('+') {
+        Some(id) => (id.to_string(), true),
+        None => (id, false),
+    };
+    let asked = matches!(params.preview.as_deref(), Some("1" | "true"));
+
+    let record = match state.get_link(&id).await {
+        Ok(record) => {
+            state.metrics.redirect_hit();
+            record
+        }
+        Err(e) => {
+            if let ShortenError::IdNotFound(_) = e {
+                state.metrics.redirect_miss();
+            }
+            return Err(e);
+        }
+    };
+    let target = target::resolve(&record.url, path, query, record.query_merge)?;
+    if plus || asked || record.preview {
+        return Ok(preview::render(&record, &state.short_url(&id), &target).into_response());
+    }
+
+    if let Err(e) = state.store.record_click(&id).await {
+        warn!("Record click of {id} failed with error: {e}");
+    }
+    Ok(record.redirect_type.to(&target))
+}
+
+#[derive(Clone, Deserialize, ToSchema)]
+struct ShortenReq {
+    /// The url to shorten, without scheme, e.g. `www.rust-lang.org`.
+    uri: String,
+    #[serde(flatten)]
+    options: LinkOptions,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ShortenRes {
+    /// The short url.
+    url: String,
+}
+
+/// A link as seen by its owner.
+#[derive(Serialize, ToSchema)]
+struct Link {
+    id: String,
+    /// The short url.
+    url: String,
+    /// Where the short url goes.
+    target: String,
+    preview: bool,
+    query_merge: QueryMerge,
+    redirect_type: RedirectType,
+    clicks: i64,
+    created_at: Option<String>,
+    /// HTTP status of the last check of the target, 0 if it couldn't be reached.
+    check_status: Option<i32>,
+    checked_at: Option<String>,
+    /// The last check found the target unreachable or answering with an error.
+    dead: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ListParams {
+    /// Only dead links with `true`, only live or unchecked links with `false`.
+    dead: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/shortener",
+    request_body = ShortenReq,
+    security(("api_key" = [])),
+    responses(
+        (status = 201, description = "Shortened", body = ShortenRes),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+        (status = 403, description = "Domain blocked", body = ErrorBody),
+        (status = 422, description = "Illegal url", body = ErrorBody),
+        (status = 429, description = "Rate limited", body = ErrorBody, headers(("retry-after" = u64))),
+        (status = 500, description = "Server error", body = ErrorBody),
+    )
+)]
+async fn shorten(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Json(req): Json<ShortenReq>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let id = state.shorten(&req.uri, &owner, &req.options).await?;
+    let url = state.short_url(&id);
+    Ok((StatusCode::CREATED, Json(ShortenRes { url })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/links",
+    params(ListParams),
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Links of the caller", body = [Link]),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+    )
+)]
+async fn list_links(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Query(params): Query<ListParams>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let links: Vec<_> = state
+        .store
+        .list(&owner)
+        .await?
+        .into_iter()
+        .filter(|record| params.dead.is_none_or(|dead| record.is_dead() == dead))
+        .map(|record| state.link(record))
+        .collect();
+    Ok(Json(links))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/links/{id}",
+    params(("id" = String, Path, description = "Short id")),
+    request_body = LinkUpdate,
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Updated", body = Link),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+        (status = 403, description = "Domain blocked", body = ErrorBody),
+        (status = 404, description = "The caller has no such link", body = ErrorBody),
+        (status = 409, description = "The caller already shortened the url", body = ErrorBody),
+        (status = 422, description = "Illegal url", body = ErrorBody),
+    )
+)]
+async fn update_link(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Path(id): Path<String>,
+    Json(update): Json<LinkUpdate>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if let Some(url) = &update.url {
+        state.check_url(url)?;
+    }
+    match state.store.update(&id, &owner, &update).await? {
+        Some(record) => Ok(Json(state.link(record))),
+        None => Err(ShortenError::IdNotFound(id)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/links/{id}",
+    params(("id" = String, Path, description = "Short id")),
+    security(("api_key" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Missing or invalid api key", body = ErrorBody),
+        (status = 404, description = "The caller has no such link", body = ErrorBody),
+    )
+)]
+async fn delete_link(
+    State(state): State<Arc<AppState>>,
+    Owner(owner): Owner,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ShortenError> {
+    if state.store.delete(&id, &owner).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ShortenError::IdNotFound(id))
+    }
+}
+
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+struct AppState {
+    #[builder(setter(into))]
+    base_url: String, // 短链接的公开地址，不带末尾的 /
+    store: Arc<dyn UrlStore>,
+    #[builder(default)]
+    api_keys: HashMap<String, String>, // api key => owner
+    #[builder(default)]
+    ids: IdGenerator,
+    #[builder(default)]
+    blocklist: Blocklist,
+    #[builder(default)]
+    limiter: RateLimiter,
+    #[builder(default)]
+    metrics: Metrics,
+    #[builder(default)]
+    draining: AtomicBool, // 收到退出信号后 /readyz 返回 503
+}
+
+impl AppState {
+    fn short_url(&self, id: &str) -> String {
+        format!("{}/{id}", self.base_url)
+    }
+
+    fn link(&self, record: UrlRecord) -> Link {
+        Link {
+            dead: record.is_dead(),
+            check_status: record.check_status,
+            checked_at: record.checked_at.map(|t| t.to_rfc3339()),
+            url: self.short_url(&record.id),
+            id: record.id,
+            target: record.url,
+            preview: record.preview,
+            query_merge: record.query_merge,
+            redirect_type: record.redirect_type,
+            clicks: record.clicks,
+            created_at: record.created_at.map(|t| t.to_rfc3339()),
+        }
+    }
+
+    /// Reject urls which can't be parsed or point to a blocked domain.
+    fn check_url(&self, url: &str) -> Result<(), ShortenError> {
+        let parsed = Url::parse(format!("http://{}", url).as_str())?;
+        match parsed.host_str() {
+            Some(host) if self.blocklist.is_blocked(host) => {
+                Err(ShortenError::DomainBlocked(host.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn shorten(
+        &self,
+        url: &str,
+        owner: &str,
+        options: &LinkOptions,
+    ) -> Result<String, ShortenError> {
+        self.check_url(url)?;
+
+        for i in 0..MAX_SHORTEN_TRY {
+            let new = NewUrl {
+                id: self.ids.generate(&*self.store, url, owner, i).await?,
+                url: url.to_string(),
+                options: options.clone(),
+            };
+            match self.store.insert(&new, owner).await {
+                Ok(id) => return Ok(id),
+                Err(ShortenError::IdConflict(id)) => {
+                    warn!(
+                        "The {} encounter id {id} conflict: unique_violation, try again!",
+                        i + 1
+                    );
+                    self.metrics.shorten_retry(1);
+                    self.ids.on_conflict();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(ShortenError::UrlMaxTrySave)
+    }
+
+    async fn get_link(&self, id: &str) -> Result<UrlRecord, ShortenError> {
+        if !id::is_valid_id(id) {
+            return Err(ShortenError::IdIllegal);
+        }
+        if let Some(record) = self.store.get(id).await? {
+            Ok(record)
+        } else {
+            Err(ShortenError::IdNotFound(id.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{header, Method, Request},
+    };
+    use http_body_util::BodyExt;
+    use limit::Quota;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use store::{MemoryStore, SqliteStore};
+    use tower::ServiceExt;
+
+    const BASE_URL: &str = "https://s.example.com";
+    const ALICE_KEY: &str = "alice-key";
+    const BOB_KEY: &str = "bob-key";
+
+    fn test_app() -> Router {
+        app(test_state())
+    }
+
+    fn test_state() -> Arc<AppState> {
+        let api_keys = auth::parse_api_keys("alice-key=alice,bob-key=bob");
+        let state = AppStateBuilder::default()
+            .store(Arc::new(MemoryStore::default()))
+            .base_url(BASE_URL)
+            .api_keys(api_keys)
+            .blocklist(Blocklist::new(["evil.com"]))
+            .limiter(RateLimiter::new(
+                Quota::new(3, Duration::from_secs(60)),
+                Quota::new(5, Duration::from_secs(60)),
+            ));
+        Arc::new(state.build().unwrap())
+    }
+
+    async fn send(
+        app: &Router,
+        method: Method,
+        path: &str,
+        key: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, hyper::HeaderMap, Value) {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(key) = key {
+            req = req.header("x-api-key", key);
+        }
+        let req = match body {
+            Some(body) => req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => req.body(Body::empty()),
+        }
+        .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            parts.status,
+            parts.headers,
+            serde_json::from_slice(&body).unwrap_or(Value::Null),
+        )
+    }
+
+    async fn post_shorten(app: &Router, key: &str, uri: &str) -> (StatusCode, Value) {
+        let body = json!({ "uri": uri });
+        let (status, _, body) = send(app, Method::POST, "/shortener", Some(key), Some(body)).await;
+        (status, body)
+    }
+
+    fn id_of(body: &Value) -> String {
+        let url = body["url"].as_str().unwrap();
+        url.rsplit('/').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn shorten_then_redirect() {
+        let app = test_app();
+
+        let (status, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = id_of(&body);
+        assert_eq!(body["url"], format!("{BASE_URL}/{id}"));
+
+        let (status, headers, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers[header::LOCATION], "https://www.rust-lang.org");
+    }
+
+    #[tokio::test]
+    async fn shorten_same_url_returns_same_id() {
+        let app = test_app();
+
+        let (_, first) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let (_, second) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        assert_eq!(id_of(&first), id_of(&second));
+    }
+
+    #[tokio::test]
+    async fn shorten_requires_api_key() {
+        let app = test_app();
+
+        let body = Some(json!({ "uri": "www.rust-lang.org" }));
+        let (status, _, _) = send(&app, Method::POST, "/shortener", None, body.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = send(&app, Method::POST, "/shortener", Some("bad"), body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn shorten_is_rate_limited() {
+        let app = test_app();
+
+        for i in 0..5 {
+            let (status, _) = post_shorten(&app, ALICE_KEY, &format!("www.{i}.com")).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let body = Some(json!({ "uri": "www.rust-lang.org" }));
+        let (status, headers, _) =
+            send(&app, Method::POST, "/shortener", Some(ALICE_KEY), body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[header::RETRY_AFTER], "12");
+        // 其他 key 不受影响，读接口也不限流
+        let (status, _) = post_shorten(&app, BOB_KEY, "www.rust-lang.org").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, _) = send(&app, Method::GET, "/links", Some(ALICE_KEY), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn shorten_is_rate_limited_per_ip() {
+        let app = test_app();
+        // axum::serve 用 into_make_service_with_connect_info 时才有客户端地址
+        let shorten_from = |ip: &str, i: usize| {
+            let addr: SocketAddr = format!("{ip}:40000").parse().unwrap();
+            let body = json!({ "uri": format!("www.{i}.com") });
+            let mut req = Request::post("/shortener")
+                .header("x-api-key", ALICE_KEY)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            req.extensions_mut().insert(ConnectInfo(addr));
+            app.clone().oneshot(req)
+        };
+
+        for i in 0..3 {
+            let res = shorten_from("10.0.0.1", i).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        let res = shorten_from("10.0.0.1", 3).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "20");
+        // 同一个 key 从别的地址来不受影响
+        let res = shorten_from("10.0.0.2", 4).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn shorten_rejects_blocked_domain() {
+        let app = test_app();
+
+        let (status, _) = post_shorten(&app, ALICE_KEY, "login.evil.com/account").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn redirect_errors() {
+        let app = test_app();
+
+        let (status, _, body) = send(&app, Method::GET, "/abc.def", None, None).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "id_illegal");
+        let (status, _, body) = send(&app, Method::GET, "/abcdef", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "id_not_found");
+        assert_eq!(body["message"], "the id abcdef can't not found");
+        let (status, _, body) = send(&app, Method::GET, "/", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "route_not_found");
+    }
+
+    #[tokio::test]
+    async fn rejections_are_json() {
+        let app = test_app();
+
+        let req = Request::post("/shortener")
+            .header("x-api-key", ALICE_KEY)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"uri\":"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "json_illegal");
+
+        let (status, _, body) = send(&app, Method::POST, "/shortener", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let path = format!("/qr/{}?size=abc", id_of(&body));
+        let (status, _, body) = send(&app, Method::GET, &path, None, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "query_illegal");
+    }
+
+    #[tokio::test]
+    async fn metrics_count_requests_and_redirects() {
+        let app = test_app();
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let path = format!("/{}", id_of(&body));
+        send(&app, Method::GET, &path, None, None).await;
+        send(&app, Method::GET, "/abcdef", None, None).await;
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains(r#"shortener_redirects_total{result="hit"} 1"#));
+        assert!(text.contains(r#"shortener_redirects_total{result="miss"} 1"#));
+        assert!(text.contains(
+            r#"shortener_http_requests_total{method="GET",route="/:id",status="303"} 1"#
+        ));
+        assert!(text.contains(
+            r#"shortener_http_requests_total{method="POST",route="/shortener",status="201"} 1"#
+        ));
+        assert!(text.contains("shortener_http_request_duration_seconds_bucket"));
+        assert!(text.contains("shortener_shorten_retries_total 0"));
+    }
+
+    #[tokio::test]
+    async fn health_probes() -> Result<()> {
+        let app = test_app();
+        let (status, _, body) = send(&app, Method::GET, "/healthz", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        let (status, _, _) = send(&app, Method::GET, "/readyz", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // sqlite 库被关闭后就不再 ready
+        let store = SqliteStore::try_new("sqlite::memory:", 1).await?;
+        store.close().await;
+        let state = AppStateBuilder::default()
+            .base_url(BASE_URL)
+            .store(Arc::new(store))
+            .build()?;
+        let app = super::app(Arc::new(state));
+        let (status, _, body) = send(&app, Method::GET, "/readyz", None, None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["code"], "not_ready");
+        let (status, _, _) = send(&app, Method::GET, "/healthz", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readyz_fails_while_draining() -> Result<()> {
+        let state = test_state();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let draining = state.clone();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app(state).into_make_service())
+                .with_graceful_shutdown(async move {
+                    let _ = stopped.await;
+                    health::drain(&draining, Duration::from_millis(500)).await;
+                })
+                .await
+        });
+        let client = reqwest::Client::new();
+        let readyz = format!("http://{addr}/readyz");
+        assert_eq!(client.get(&readyz).send().await?.status(), StatusCode::OK);
+
+        // 收到退出信号后仍然接受连接，但不再 ready
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&readyz).send().await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = client.get(format!("http://{addr}/healthz")).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        tokio::time::timeout(Duration::from_secs(2), server).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn openapi_spec() {
+        let app = test_app();
+        let (status, _, spec) = send(&app, Method::GET, "/openapi.json", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        for path in [
+            "/{id}",
+            "/qr/{id}",
+            "/shortener",
+            "/shortener/bulk",
+            "/links/{id}",
+        ] {
+            assert!(spec["paths"][path].is_object(), "missing {path}");
+        }
+        assert!(spec["components"]["schemas"]["ErrorBody"].is_object());
+        assert!(spec["components"]["securitySchemes"]["api_key"].is_object());
+    }
+
+    #[tokio::test]
+    async fn links_are_scoped_to_owner() {
+        let app = test_app();
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let id = id_of(&body);
+        let path = format!("/links/{id}");
+
+        let (_, _, links) = send(&app, Method::GET, "/links", Some(ALICE_KEY), None).await;
+        assert_eq!(links[0]["id"], id.as_str());
+        assert_eq!(links[0]["target"], "www.rust-lang.org");
+        let (_, _, links) = send(&app, Method::GET, "/links", Some(BOB_KEY), None).await;
+        assert_eq!(links, json!([]));
+
+        // bob 不能修改或删除 alice 的链接
+        let body = Some(json!({ "uri": "www.bing.com" }));
+        let (status, _, _) = send(&app, Method::PATCH, &path, Some(BOB_KEY), body.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&app, Method::DELETE, &path, Some(BOB_KEY), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _, link) = send(&app, Method::PATCH, &path, Some(ALICE_KEY), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(link["target"], "www.bing.com");
+        let (_, headers, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(headers[header::LOCATION], "https://www.bing.com");
+
+        let (status, _, _) = send(&app, Method::DELETE, &path, Some(ALICE_KEY), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn bulk_shorten_reports_each_item() {
+        let app = test_app();
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let id = id_of(&body);
+
+        let body = json!([
+            { "uri": "www.rust-lang.org" },
+            { "uri": "crates.io" },
+            { "uri": "www.rust lang.org" },
+        ]);
+        let path = "/shortener/bulk";
+        let (status, _, items) = send(&app, Method::POST, path, Some(ALICE_KEY), Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(id_of(&items[0]), id);
+        assert!(items[1]["url"].is_string());
+        assert_eq!(items[2]["error"]["code"], "url_illegal");
+
+        let (_, _, links) = send(&app, Method::GET, "/links", Some(ALICE_KEY), None).await;
+        assert_eq!(links.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn csv_import_then_export() {
+        let app = test_app();
+
+        let req = Request::post("/shortener/import")
+            .header("x-api-key", ALICE_KEY)
+            .header(header::CONTENT_TYPE, "text/csv")
+            .body(Body::from("uri\nwww.rust-lang.org\ncrates.io\n"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let imported = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<_> = imported.lines().collect();
+        assert_eq!(lines[0], "uri,url,error");
+        assert!(lines[1].starts_with(&format!("www.rust-lang.org,{BASE_URL}/")));
+        assert_eq!(lines.len(), 3);
+
+        let req = Request::get("/links/export")
+            .header("x-api-key", ALICE_KEY)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let exported = String::from_utf8(body.to_vec()).unwrap();
+        assert!(exported.starts_with("id,url,target\n"));
+        assert!(exported.contains(",crates.io\n"));
+    }
+
+    #[tokio::test]
+    async fn redirect_forwards_query_and_path() {
+        let app = test_app();
+        let location =
+            |headers: &hyper::HeaderMap| headers[header::LOCATION].to_str().unwrap().to_string();
+
+        // 默认丢弃访问时的 query 参数
+        let (_, body) = post_shorten(&app, ALICE_KEY, "shop.com/sale?page=1").await;
+        let id = id_of(&body);
+        let path = format!("/{id}?utm_source=x");
+        let (status, headers, _) = send(&app, Method::GET, &path, None, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(location(&headers), "https://shop.com/sale?page=1");
+
+        let body = Some(json!({ "query_merge": "append" }));
+        let (_, _, link) = send(
+            &app,
+            Method::PATCH,
+            &format!("/links/{id}"),
+            Some(ALICE_KEY),
+            body,
+        )
+        .await;
+        assert_eq!(link["query_merge"], "append");
+        let (_, headers, _) = send(&app, Method::GET, &path, None, None).await;
+        assert_eq!(
+            location(&headers),
+            "https://shop.com/sale?page=1&utm_source=x"
+        );
+
+        let body = Some(json!({ "uri": "shop.com/{path}", "query_merge": "merge" }));
+        let (_, _, body) = send(&app, Method::POST, "/shortener", Some(ALICE_KEY), body).await;
+        let id = id_of(&body);
+        let path = format!("/{id}/shoes/red%3Fsize?utm_source=x");
+        let (status, headers, _) = send(&app, Method::GET, &path, None, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(
+            location(&headers),
+            "https://shop.com/shoes/red%3Fsize?utm_source=x"
+        );
+        // 二维码在 /qr/{id}，/{id}/qr 也是路径
+        let (status, headers, _) = send(&app, Method::GET, &format!("/{id}/qr"), None, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(location(&headers), "https://shop.com/qr");
+
+        // 非模板链接不接受路径
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let path = format!("/{}/shoes", id_of(&body));
+        let (status, _, body) = send(&app, Method::GET, &path, None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "route_not_found");
+    }
+
+    #[tokio::test]
+    async fn redirect_uses_link_redirect_type() {
+        let app = test_app();
+
+        let body = Some(json!({ "uri": "www.rust-lang.org", "redirect_type": 301 }));
+        let (status, _, body) = send(&app, Method::POST, "/shortener", Some(ALICE_KEY), body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = id_of(&body);
+        let (status, headers, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers[header::LOCATION], "https://www.rust-lang.org");
+
+        let body = Some(json!({ "redirect_type": 307 }));
+        let path = format!("/links/{id}");
+        let (_, _, link) = send(&app, Method::PATCH, &path, Some(ALICE_KEY), body).await;
+        assert_eq!(link["redirect_type"], 307);
+        let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+
+        let body = Some(json!({ "uri": "crates.io", "redirect_type": 200 }));
+        let (status, _, body) = send(&app, Method::POST, "/shortener", Some(ALICE_KEY), body).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "json_illegal");
+    }
+
+    #[tokio::test]
+    async fn dead_links_are_flagged() -> Result<()> {
+        let addr = checker::stub_server().await;
+        let state = test_state();
+        let app = app(state.clone());
+        let (_, body) = post_shorten(&app, ALICE_KEY, &format!("{addr}/ok")).await;
+        let ok = id_of(&body);
+        let (_, body) = post_shorten(&app, ALICE_KEY, &format!("{addr}/gone")).await;
+        let gone = id_of(&body);
+
+        // 还没检查过的链接不算失效
+        let (_, _, links) =
+            send(&app, Method::GET, "/links?dead=true", Some(ALICE_KEY), None).await;
+        assert_eq!(links.as_array().unwrap().len(), 0);
+
+        let checker =
+            LinkChecker::new(Duration::from_secs(60), Duration::from_secs(1), true)?.plain_http();
+        assert_eq!(checker.run_once(&state).await?, 2);
+        // 刚检查过的链接不会马上再检查
+        assert_eq!(checker.run_once(&state).await?, 0);
+
+        let (_, _, links) =
+            send(&app, Method::GET, "/links?dead=true", Some(ALICE_KEY), None).await;
+        let links = links.as_array().unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0]["id"], gone.as_str());
+        assert_eq!(links[0]["check_status"], 404);
+        assert!(links[0]["checked_at"].is_string());
+
+        let (_, _, links) = send(
+            &app,
+            Method::GET,
+            "/links?dead=false",
+            Some(ALICE_KEY),
+            None,
+        )
+        .await;
+        let links = links.as_array().unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0]["id"], ok.as_str());
+        assert_eq!(links[0]["dead"], false);
+
+        // 修改目标后需要重新检查
+        let body = Some(json!({ "uri": format!("{addr}/get-only") }));
+        let path = format!("/links/{gone}");
+        let (_, _, link) = send(&app, Method::PATCH, &path, Some(ALICE_KEY), body).await;
+        assert_eq!(link["dead"], false);
+        assert!(link["check_status"].is_null());
+        assert_eq!(checker.run_once(&state).await?, 1);
+        let (_, _, links) =
+            send(&app, Method::GET, "/links?dead=true", Some(ALICE_KEY), None).await;
+        assert_eq!(links.as_array().unwrap().len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preview_page() {
+        let app = test_app();
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let id = id_of(&body);
+
+        for path in [format!("/{id}+"), format!("/{id}?preview=1")] {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            let html = String::from_utf8(body.to_vec()).unwrap();
+            assert!(html.contains(r#"<a href="https://www.rust-lang.org""#));
+            assert!(html.contains("<td>0</td>"));
+        }
+
+        // 只有真正跳转才计数
+        let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        let (_, _, links) = send(&app, Method::GET, "/links", Some(ALICE_KEY), None).await;
+        assert_eq!(links[0]["clicks"], 1);
+
+        // 设置 preview 后所有访问者都看到预览页
+        let path = format!("/links/{id}");
+        let body = Some(json!({ "preview": true }));
+        let (_, _, link) = send(&app, Method::PATCH, &path, Some(ALICE_KEY), body).await;
+        assert_eq!(link["preview"], true);
+        assert_eq!(link["target"], "www.rust-lang.org");
+        let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body = Some(json!({ "uri": "crates.io", "preview": true }));
+        let (_, _, body) = send(&app, Method::POST, "/shortener", Some(ALICE_KEY), body).await;
+        let (status, _, _) =
+            send(&app, Method::GET, &format!("/{}", id_of(&body)), None, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn qr_code_formats() {
+        let app = test_app();
+        let (_, body) = post_shorten(&app, ALICE_KEY, "www.rust-lang.org").await;
+        let id = id_of(&body);
+
+        let req = Request::get(format!("/qr/{id}?size=128")).body(Body::empty());
+        let res = app.clone().oneshot(req.unwrap()).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/png");
+        let png = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(png.starts_with(b"\x89PNG"));
+
+        let req = Request::get(format!("/qr/{id}?format=svg")).body(Body::empty());
+        let res = app.clone().oneshot(req.unwrap()).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/svg+xml");
+
+        let (status, _, _) = send(&app, Method::GET, &format!("/qr/{id}?size=1"), None, None).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _, _) = send(&app, Method::GET, "/qr/abcdef", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    fn new_url(id: &str, url: &str) -> NewUrl {
+        NewUrl {
+            id: id.to_string(),
+            url: url.to_string(),
+            options: LinkOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_works() -> Result<()> {
+        let store = SqliteStore::try_new("sqlite::memory:", 1).await?;
+        let version = store.migrate().await?;
+        assert!(version > 0);
+        // 重复迁移不会出错
+        assert_eq!(store.migrate().await?, version);
+
+        let id = store
+            .insert(&new_url("000000", "www.bing.com"), "alice")
+            .await?;
+        assert_eq!(id, "000000");
+        // url 已存在时返回原来的 id
+        let id = store
+            .insert(&new_url("111111", "www.bing.com"), "alice")
+            .await?;
+        assert_eq!(id, "000000");
+        // id 已被占用时返回冲突
+        assert!(matches!(
+            store
+                .insert(&new_url("000000", "www.soso.com"), "alice")
+                .await,
+            Err(ShortenError::IdConflict(_))
+        ));
+        let record = store.get("000000").await?.unwrap();
+        assert_eq!(record.url, "www.bing.com");
+        assert!(record.created_at.is_some());
+        assert!(store.get("222222").await?.is_none());
+
+        store.record_click("000000").await?;
+        assert_eq!(store.get("000000").await?.unwrap().clicks, 1);
+
+        let items = [
+            new_url("222222", "www.bing.com"),
+            new_url("000000", "crates.io"),
+            new_url("333333", "crates.io"),
+        ];
+        let ids = store.insert_many(&items, "alice").await?;
+        assert_eq!(
+            ids,
+            [Some("000000".to_string()), None, Some("333333".to_string())]
+        );
+
+        assert_eq!(store.next_sequence().await?, 1);
+        assert_eq!(store.next_sequence().await?, 2);
+
+        let update = LinkUpdate {
+            url: Some("www.soso.com".to_string()),
+            ..Default::default()
+        };
+        assert!(store.update("000000", "bob", &update).await?.is_none());
+        let record = store.update("000000", "alice", &update).await?.unwrap();
+        assert_eq!(record.url, "www.soso.com");
+        assert!(!record.preview);
+        let update = LinkUpdate {
+            url: None,
+            preview: Some(true),
+            query_merge: Some(QueryMerge::Merge),
+            redirect_type: Some(RedirectType::PermanentRedirect),
+        };
+        let record = store.update("000000", "alice", &update).await?.unwrap();
+        assert_eq!(record.url, "www.soso.com");
+        assert!(record.preview);
+        assert_eq!(record.query_merge, QueryMerge::Merge);
+        let record = store.get("000000").await?.unwrap();
+        assert_eq!(record.query_merge, QueryMerge::Merge);
+        assert_eq!(record.redirect_type, RedirectType::PermanentRedirect);
+
+        let now = chrono::Utc::now();
+        assert_eq!(store.due_for_check(now, 10).await?.len(), 2);
+        store.record_check("000000", 404, now).await?;
+        let due = store.due_for_check(now, 10).await?;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, "333333");
+        let record = store.get("000000").await?.unwrap();
+        assert_eq!(record.check_status, Some(404));
+        assert!(record.is_dead());
+
+        assert_eq!(store.list("alice").await?.len(), 2);
+        assert!(!store.delete("000000", "bob").await?);
+        assert!(store.delete("000000", "alice").await?);
+        assert_eq!(store.list("alice").await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Registry,
+    TextEncoder,
+};
+
+use crate::{error::ShortenError, AppState};
+
+/// Prometheus metrics of the shortener, scraped from `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    redirects: IntCounterVec,
+    shorten_retries: IntCounter,
+    link_checks: IntCounterVec,
+    db_connections: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let requests = IntCounterVec::new(
+            opts!(
+                "shortener_http_requests_total",
+                "HTTP requests by route and status"
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("无法创建指标");
+        let latency = HistogramVec::new(
+            histogram_opts!(
+                "shortener_http_request_duration_seconds",
+                "HTTP request latency by route"
+            ),
+            &["method", "route"],
+        )
+        .expect("无法创建指标");
+        let redirects = IntCounterVec::new(
+            opts!(
+                "shortener_redirects_total",
+                "Redirect lookups by result, hit or miss"
+            ),
+            &["result"],
+        )
+        .expect("无法创建指标");
+        let shorten_retries = IntCounter::new(
+            "shortener_shorten_retries_total",
+            "Inserts retried because the generated id was taken",
+        )
+        .expect("无法创建指标");
+        let link_checks = IntCounterVec::new(
+            opts!(
+                "shortener_link_checks_total",
+                "Target checks by result, alive or dead"
+            ),
+            &["result"],
+        )
+        .expect("无法创建指标");
+        let db_connections = IntGaugeVec::new(
+            opts!(
+                "shortener_db_connections",
+                "Database pool connections by state"
+            ),
+            &["state"],
+        )
+        .expect("无法创建指标");
+
+        let registry = Registry::new();
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(latency.clone()),
+            Box::new(redirects.clone()),
+            Box::new(shorten_retries.clone()),
+            Box::new(link_checks.clone()),
+            Box::new(db_connections.clone()),
+        ] {
+            registry.register(collector).expect("无法注册指标");
+        }
+
+        Self {
+            registry,
+            requests,
+            latency,
+            redirects,
+            shorten_retries,
+            link_checks,
+            db_connections,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn redirect_hit(&self) {
+        self.redirects.with_label_values(&["hit"]).inc();
+    }
+
+    pub fn redirect_miss(&self) {
+        self.redirects.with_label_values(&["miss"]).inc();
+    }
+
+    pub fn shorten_retry(&self, n: u64) {
+        self.shorten_retries.inc_by(n);
+    }
+
+    pub fn link_checked(&self, status: i32) {
+        let result = if status == 0 || status >= 400 {
+            "dead"
+        } else {
+            "alive"
+        };
+        self.link_checks.with_label_values(&[result]).inc();
+    }
+}
+
+/// Count and time every request, labelled with the matched route rather than the
+/// raw path so short ids don't blow up the label cardinality.
+pub async fn track(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let res = next.run(req).await;
+    let metrics = &state.metrics;
+    metrics
+        .latency
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&method, &route, res.status().as_str()])
+        .inc();
+    res
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Result<Response, ShortenError> {
+    let metrics = &state.metrics;
+    // 连接池的使用情况在抓取时读取
+    if let Some(stats) = state.store.pool_stats() {
+        let idle = stats.idle as i64;
+        let connections = &metrics.db_connections;
+        connections.with_label_values(&["idle"]).set(idle);
+        connections
+            .with_label_values(&["active"])
+            .set(stats.size as i64 - idle);
+        connections
+            .with_label_values(&["max"])
+            .set(stats.max as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    encoder
+        .encode(&metrics.registry.gather(), &mut body)
+        .map_err(|_| ShortenError::Unknown)?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response())
+}
@@ -0,0 +1,17 @@
+-- 迁移之前的版本在启动时建表，这里的语句都是幂等的，旧库也能直接升级
+CREATE TABLE IF NOT EXISTS urls (
+    id VARCHAR(21) PRIMARY KEY,
+    url TEXT NOT NULL
+);
+
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS owner TEXT;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS preview BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS clicks BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(21);
+
+-- url 按 owner 唯一
+ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key;
+CREATE UNIQUE INDEX IF NOT EXISTS urls_owner_url_key ON urls (owner, url);
+
+CREATE SEQUENCE IF NOT EXISTS urls_id_seq;
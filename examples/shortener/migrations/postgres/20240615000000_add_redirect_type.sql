@@ -0,0 +1,2 @@
+-- 跳转使用的状态码: 301 | 302 | 303 | 307 | 308
+ALTER TABLE urls ADD COLUMN redirect_type INTEGER NOT NULL DEFAULT 303;
@@ -0,0 +1,4 @@
+-- 目标链接的检查结果: 最近一次的 HTTP 状态码 (0 表示无法访问) 和检查时间
+ALTER TABLE urls ADD COLUMN check_status INTEGER;
+ALTER TABLE urls ADD COLUMN checked_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS urls_checked_at ON urls (checked_at);
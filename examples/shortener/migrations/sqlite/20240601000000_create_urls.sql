@@ -0,0 +1,17 @@
+-- 迁移之前的版本在启动时建表，这里的语句都是幂等的，旧库也能直接升级
+CREATE TABLE IF NOT EXISTS urls (
+    id VARCHAR(21) PRIMARY KEY,
+    url TEXT NOT NULL,
+    owner TEXT,
+    preview BOOLEAN NOT NULL DEFAULT FALSE,
+    clicks INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (owner, url)
+);
+
+CREATE TABLE IF NOT EXISTS urls_id_seq (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO urls_id_seq (name, value) VALUES ('urls', 0);
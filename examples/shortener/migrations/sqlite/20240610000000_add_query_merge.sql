@@ -0,0 +1,2 @@
+-- 访问时 query 参数的处理方式: ignore | append | merge
+ALTER TABLE urls ADD COLUMN query_merge TEXT NOT NULL DEFAULT 'ignore';
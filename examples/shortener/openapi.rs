@@ -0,0 +1,59 @@
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    bulk::BulkItem,
+    error::ErrorBody,
+    extract::Json,
+    store::{LinkOptions, LinkUpdate},
+    target::{QueryMerge, RedirectType},
+    Link, ShortenReq, ShortenRes,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "URL shortener"),
+    paths(
+        crate::redirect,
+        crate::redirect_path,
+        crate::qr::qr_code,
+        crate::shorten,
+        crate::bulk::bulk_shorten,
+        crate::bulk::import_csv,
+        crate::list_links,
+        crate::bulk::export_csv,
+        crate::update_link,
+        crate::delete_link,
+    ),
+    components(schemas(
+        ShortenReq,
+        ShortenRes,
+        LinkOptions,
+        LinkUpdate,
+        QueryMerge,
+        RedirectType,
+        Link,
+        BulkItem,
+        ErrorBody
+    )),
+    modifiers(&ApiKeyAuth)
+)]
+pub struct ApiDoc;
+
+struct ApiKeyAuth;
+
+impl Modify for ApiKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+        );
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
@@ -0,0 +1,63 @@
+use axum::response::Html;
+
+use crate::store::UrlRecord;
+
+/// The interstitial page showing where `record` goes instead of redirecting.
+pub fn render(record: &UrlRecord, short_url: &str, target: &str) -> Html<String> {
+    let created_at = record
+        .created_at
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let short_url = escape(short_url);
+    let target = escape(target);
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<title>{short_url}</title>
+</head>
+<body>
+<h1>{short_url}</h1>
+<p>This link goes to:</p>
+<p><a href="{target}" rel="noopener noreferrer nofollow">{target}</a></p>
+<table>
+<tr><th>Created</th><td>{created_at}</td></tr>
+<tr><th>Clicks</th><td>{clicks}</td></tr>
+</table>
+</body>
+</html>
+"#,
+        clicks = record.clicks,
+    ))
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_works() {
+        assert_eq!(
+            escape(r#"a.com/?q=<script>"&'"#),
+            "a.com/?q=&lt;script&gt;&quot;&amp;&#39;"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::{io::Cursor, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::IntoResponse,
+};
+use image::{ImageFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{error::ShortenError, extract::Query, AppState};
+
+const DEFAULT_QR_SIZE: u32 = 256;
+pub const MIN_QR_SIZE: u32 = 64;
+pub const MAX_QR_SIZE: u32 = 2048;
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct QrParams {
+    #[serde(default)]
+    #[param(inline)]
+    format: QrFormat,
+    /// Minimum width in pixels, 64 to 2048, defaults to 256.
+    size: Option<u32>,
+}
+
+/// Render the short url of `id` as a QR code, `size` is the minimum width in pixels.
+/// Served at `/qr/{id}`, `/{id}/qr` is the `qr` path of a templated target.
+#[utoipa::path(
+    get,
+    path = "/qr/{id}",
+    params(("id" = String, Path, description = "Short id"), QrParams),
+    responses(
+        (status = 200, description = "The QR code", content_type = "image/png"),
+        (status = 404, description = "Unknown id", body = ErrorBody),
+        (status = 422, description = "Illegal id or size", body = ErrorBody),
+    )
+)]
+pub async fn qr_code(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<QrParams>,
+) -> Result<impl IntoResponse, ShortenError> {
+    let size = params.size.unwrap_or(DEFAULT_QR_SIZE);
+    if !(MIN_QR_SIZE..=MAX_QR_SIZE).contains(&size) {
+        return Err(ShortenError::QrSizeIllegal(size));
+    }
+    // 只为存在的链接生成二维码
+    state.get_link(&id).await?;
+
+    let code = QrCode::new(state.short_url(&id)).map_err(|_| ShortenError::Unknown)?;
+    let response = match params.format {
+        QrFormat::Png => {
+            let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+            let mut png = Cursor::new(vec![]);
+            image
+                .write_to(&mut png, ImageFormat::Png)
+                .map_err(|_| ShortenError::Unknown)?;
+            ([(header::CONTENT_TYPE, "image/png")], png.into_inner()).into_response()
+        }
+        QrFormat::Svg => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            ([(header::CONTENT_TYPE, "image/svg+xml")], image).into_response()
+        }
+    };
+    Ok(response)
+}
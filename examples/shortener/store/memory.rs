@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::{LinkUpdate, NewUrl, UrlRecord, UrlStore};
+use crate::error::ShortenError;
+
+/// In-process store, used by tests and for running the shortener without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<Inner>,
+    sequence: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    urls: HashMap<String, UrlRecord>,       // id => record
+    ids: HashMap<(String, String), String>, // (owner, url) => id
+}
+
+impl MemoryStore {
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Inner>, ShortenError> {
+        self.inner.lock().map_err(|_| ShortenError::Unknown)
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, new: &NewUrl, owner: &str) -> Result<String, ShortenError> {
+        let key = (owner.to_string(), new.url.clone());
+        if let Some(id) = self.ids.get(&key) {
+            return Ok(id.clone());
+        }
+        if self.urls.contains_key(&new.id) {
+            return Err(ShortenError::IdConflict(new.id.clone()));
+        }
+        let record = UrlRecord {
+            id: new.id.clone(),
+            url: new.url.clone(),
+            owner: Some(owner.to_string()),
+            preview: new.options.preview,
+            query_merge: new.options.query_merge,
+            redirect_type: new.options.redirect_type,
+            clicks: 0,
+            created_at: Some(Utc::now()),
+            check_status: None,
+            checked_at: None,
+        };
+        self.urls.insert(new.id.clone(), record);
+        self.ids.insert(key, new.id.clone());
+        Ok(new.id.clone())
+    }
+}
+
+#[async_trait]
+impl UrlStore for MemoryStore {
+    async fn migrate(&self) -> Result<i64, ShortenError> {
+        Ok(0)
+    }
+
+    async fn insert(&self, new: &NewUrl, owner: &str) -> Result<String, ShortenError> {
+        self.lock()?.insert(new, owner)
+    }
+
+    async fn insert_many(
+        &self,
+        items: &[NewUrl],
+        owner: &str,
+    ) -> Result<Vec<Option<String>>, ShortenError> {
+        let mut inner = self.lock()?;
+        let mut ids = Vec::with_capacity(items.len());
+        for new in items {
+            match inner.insert(new, owner) {
+                Ok(id) => ids.push(Some(id)),
+                Err(ShortenError::IdConflict(_)) => ids.push(None),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRecord>, ShortenError> {
+        Ok(self.lock()?.urls.get(id).cloned())
+    }
+
+    async fn record_click(&self, id: &str) -> Result<(), ShortenError> {
+        if let Some(record) = self.lock()?.urls.get_mut(id) {
+            record.clicks += 1;
+        }
+        Ok(())
+    }
+
+    async fn next_sequence(&self) -> Result<u64, ShortenError> {
+        Ok(self.sequence.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    async fn due_for_check(
+        &self,
+        before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<UrlRecord>, ShortenError> {
+        let inner = self.lock()?;
+        let mut records: Vec<_> = inner
+            .urls
+            .values()
+            .filter(|r| r.checked_at.is_none_or(|at| at < before))
+            .cloned()
+            .collect();
+        // None 排在最前面
+        records.sort_by(|a, b| (a.checked_at, &a.id).cmp(&(b.checked_at, &b.id)));
+        records.truncate(limit.max(0) as usize);
+        Ok(records)
+    }
+
+    async fn record_check(
+        &self,
+        id: &str,
+        status: i32,
+        at: DateTime<Utc>,
+    ) -> Result<(), ShortenError> {
+        if let Some(record) = self.lock()?.urls.get_mut(id) {
+            record.check_status = Some(status);
+            record.checked_at = Some(at);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<UrlRecord>, ShortenError> {
+        let inner = self.lock()?;
+        let mut records: Vec<_> = inner
+            .urls
+            .values()
+            .filter(|r| r.owner.as_deref() == Some(owner))
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(records)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        owner: &str,
+        update: &LinkUpdate,
+    ) -> Result<Option<UrlRecord>, ShortenError> {
+        let mut inner = self.lock()?;
+        let old_url = match inner.urls.get(id) {
+            Some(r) if r.owner.as_deref() == Some(owner) => r.url.clone(),
+            _ => return Ok(None),
+        };
+        if let Some(url) = &update.url {
+            let key = (owner.to_string(), url.clone());
+            if *url != old_url && inner.ids.contains_key(&key) {
+                return Err(ShortenError::UrlExists(url.clone()));
+            }
+            inner.ids.remove(&(owner.to_string(), old_url));
+            inner.ids.insert(key, id.to_string());
+        }
+        let record = inner.urls.get_mut(id).ok_or(ShortenError::Unknown)?;
+        if let Some(url) = &update.url {
+            record.url = url.clone();
+            record.check_status = None;
+            record.checked_at = None;
+        }
+        if let Some(preview) = update.preview {
+            record.preview = preview;
+        }
+        if let Some(query_merge) = update.query_merge {
+            record.query_merge = query_merge;
+        }
+        if let Some(redirect_type) = update.redirect_type {
+            record.redirect_type = redirect_type;
+        }
+        Ok(Some(record.clone()))
+    }
+
+    async fn delete(&self, id: &str, owner: &str) -> Result<bool, ShortenError> {
+        let mut inner = self.lock()?;
+        match inner.urls.get(id) {
+            Some(r) if r.owner.as_deref() == Some(owner) => {
+                let url = r.url.clone();
+                inner.urls.remove(id);
+                inner.ids.remove(&(owner.to_string(), url));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{migrate::Migrator, postgres::PgPoolOptions, PgPool};
+
+use super::{LinkUpdate, NewUrl, PoolStats, UrlRecord, UrlStore};
+use crate::error::ShortenError;
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/postgres");
+
+const UNIQUE_CONSTRAINT_ERROR: &str = "23505"; // PostgreSQL 23505: duplicate key value violates unique constraint
+
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub async fn try_new(pg_url: &str, pool_size: u32) -> Result<Self, ShortenError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool_size)
+            .connect(pg_url)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl UrlStore for PgStore {
+    async fn migrate(&self) -> Result<i64, ShortenError> {
+        super::migrate(&MIGRATOR, &self.pool).await
+    }
+
+    async fn ping(&self) -> Result<(), ShortenError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some((&self.pool).into())
+    }
+
+    async fn insert(&self, new: &NewUrl, owner: &str) -> Result<String, ShortenError> {
+        let result:Result<UrlRecord,sqlx::Error> = sqlx::query_as("INSERT INTO urls (id, url, owner, preview, query_merge, redirect_type) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(owner, url) DO UPDATE SET url=EXCLUDED.url RETURNING id")
+            .bind(&new.id).bind(&new.url).bind(owner).bind(new.options.preview).bind(new.options.query_merge.to_string()).bind(u16::from(new.options.redirect_type) as i32).fetch_one(&self.pool).await;
+
+        match result {
+            Ok(url) => Ok(url.id),
+            Err(sqlx::Error::Database(e)) => match e.code() {
+                Some(code) if code == UNIQUE_CONSTRAINT_ERROR => {
+                    Err(ShortenError::IdConflict(new.id.clone()))
+                }
+                _ => Err(ShortenError::Unknown),
+            },
+            Err(e) => Err(ShortenError::DatabaseError(e)),
+        }
+    }
+
+    async fn insert_many(
+        &self,
+        items: &[NewUrl],
+        owner: &str,
+    ) -> Result<Vec<Option<String>>, ShortenError> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(items.len());
+        for new in items {
+            // 冲突时不写入，再按 (owner, url) 查一次，查不到说明是 id 冲突
+            let inserted: Option<UrlRecord> = sqlx::query_as(
+                "INSERT INTO urls (id, url, owner, preview, query_merge, redirect_type) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING RETURNING id",
+            )
+            .bind(&new.id)
+            .bind(&new.url)
+            .bind(owner)
+            .bind(new.options.preview)
+            .bind(new.options.query_merge.to_string())
+            .bind(u16::from(new.options.redirect_type) as i32)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let record = match inserted {
+                Some(record) => Some(record),
+                None => {
+                    sqlx::query_as("SELECT id FROM urls WHERE owner=$1 AND url=$2")
+                        .bind(owner)
+                        .bind(&new.url)
+                        .fetch_optional(&mut *tx)
+                        .await?
+                }
+            };
+            ids.push(record.map(|r| r.id));
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRecord>, ShortenError> {
+        let record = sqlx::query_as(
+            "SELECT id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at FROM urls WHERE id=$1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record)
+    }
+
+    async fn record_click(&self, id: &str) -> Result<(), ShortenError> {
+        sqlx::query("UPDATE urls SET clicks = clicks + 1 WHERE id=$1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn next_sequence(&self) -> Result<u64, ShortenError> {
+        let (value,): (i64,) = sqlx::query_as("SELECT nextval('urls_id_seq')")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(value as u64)
+    }
+
+    async fn due_for_check(
+        &self,
+        before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<UrlRecord>, ShortenError> {
+        let records = sqlx::query_as(
+            "SELECT id, url FROM urls WHERE checked_at IS NULL OR checked_at < $1 ORDER BY checked_at NULLS FIRST, id LIMIT $2",
+        )
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records)
+    }
+
+    async fn record_check(
+        &self,
+        id: &str,
+        status: i32,
+        at: DateTime<Utc>,
+    ) -> Result<(), ShortenError> {
+        sqlx::query("UPDATE urls SET check_status=$2, checked_at=$3 WHERE id=$1")
+            .bind(id)
+            .bind(status)
+            .bind(at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<UrlRecord>, ShortenError> {
+        let records = sqlx::query_as(
+            "SELECT id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at FROM urls WHERE owner=$1 ORDER BY id",
+        )
+        .bind(owner)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        owner: &str,
+        update: &LinkUpdate,
+    ) -> Result<Option<UrlRecord>, ShortenError> {
+        let result = sqlx::query_as(
+            r#"
+            UPDATE urls SET url=COALESCE($3, url), preview=COALESCE($4, preview),
+                query_merge=COALESCE($5, query_merge), redirect_type=COALESCE($6, redirect_type),
+                -- 目标变了就需要重新检查
+                check_status=CASE WHEN $3 IS NULL THEN check_status END,
+                checked_at=CASE WHEN $3 IS NULL THEN checked_at END
+            WHERE id=$1 AND owner=$2
+            RETURNING id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at
+            "#,
+        )
+        .bind(id)
+        .bind(owner)
+        .bind(&update.url)
+        .bind(update.preview)
+        .bind(update.query_merge.map(|q| q.to_string()))
+        .bind(update.redirect_type.map(|r| u16::from(r) as i32))
+        .fetch_optional(&self.pool)
+        .await;
+
+        match result {
+            Ok(record) => Ok(record),
+            Err(sqlx::Error::Database(e))
+                if e.code().as_deref() == Some(UNIQUE_CONSTRAINT_ERROR) =>
+            {
+                Err(ShortenError::UrlExists(
+                    update.url.clone().unwrap_or_default(),
+                ))
+            }
+            Err(e) => Err(ShortenError::DatabaseError(e)),
+        }
+    }
+
+    async fn delete(&self, id: &str, owner: &str) -> Result<bool, ShortenError> {
+        let result = sqlx::query("DELETE FROM urls WHERE id=$1 AND owner=$2")
+            .bind(id)
+            .bind(owner)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
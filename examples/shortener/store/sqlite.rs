@@ -0,0 +1,245 @@
+use std::str::FromStr;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+
+use super::{LinkUpdate, NewUrl, PoolStats, UrlRecord, UrlStore};
+use crate::error::ShortenError;
+
+static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/sqlite");
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn try_new(sqlite_url: &str, pool_size: u32) -> Result<Self, ShortenError> {
+        let options = SqliteConnectOptions::from_str(sqlite_url)?.create_if_missing(true);
+        // 每个连接都会打开一个独立的内存数据库，所以内存模式下只能保留一个连接
+        let pool = if sqlite_url.contains(":memory:") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await?
+        } else {
+            SqlitePoolOptions::new()
+                .max_connections(pool_size)
+                .connect_with(options)
+                .await?
+        };
+        Ok(Self { pool })
+    }
+
+    #[cfg(test)]
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
+#[async_trait]
+impl UrlStore for SqliteStore {
+    async fn migrate(&self) -> Result<i64, ShortenError> {
+        super::migrate(&MIGRATOR, &self.pool).await
+    }
+
+    async fn ping(&self) -> Result<(), ShortenError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some((&self.pool).into())
+    }
+
+    async fn insert(&self, new: &NewUrl, owner: &str) -> Result<String, ShortenError> {
+        let result:Result<UrlRecord,sqlx::Error> = sqlx::query_as("INSERT INTO urls (id, url, owner, preview, query_merge, redirect_type) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(owner, url) DO UPDATE SET url=EXCLUDED.url RETURNING id")
+            .bind(&new.id).bind(&new.url).bind(owner).bind(new.options.preview).bind(new.options.query_merge.to_string()).bind(u16::from(new.options.redirect_type) as i32).fetch_one(&self.pool).await;
+
+        match result {
+            Ok(url) => Ok(url.id),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                Err(ShortenError::IdConflict(new.id.clone()))
+            }
+            Err(e) => Err(ShortenError::DatabaseError(e)),
+        }
+    }
+
+    async fn insert_many(
+        &self,
+        items: &[NewUrl],
+        owner: &str,
+    ) -> Result<Vec<Option<String>>, ShortenError> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(items.len());
+        for new in items {
+            // 冲突时不写入，再按 (owner, url) 查一次，查不到说明是 id 冲突
+            let inserted: Option<UrlRecord> = sqlx::query_as(
+                "INSERT INTO urls (id, url, owner, preview, query_merge, redirect_type) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING RETURNING id",
+            )
+            .bind(&new.id)
+            .bind(&new.url)
+            .bind(owner)
+            .bind(new.options.preview)
+            .bind(new.options.query_merge.to_string())
+            .bind(u16::from(new.options.redirect_type) as i32)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let record = match inserted {
+                Some(record) => Some(record),
+                None => {
+                    sqlx::query_as("SELECT id FROM urls WHERE owner=$1 AND url=$2")
+                        .bind(owner)
+                        .bind(&new.url)
+                        .fetch_optional(&mut *tx)
+                        .await?
+                }
+            };
+            ids.push(record.map(|r| r.id));
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UrlRecord>, ShortenError> {
+        let record = sqlx::query_as(
+            "SELECT id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at FROM urls WHERE id=$1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record)
+    }
+
+    async fn record_click(&self, id: &str) -> Result<(), ShortenError> {
+        sqlx::query("UPDATE urls SET clicks = clicks + 1 WHERE id=$1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn next_sequence(&self) -> Result<u64, ShortenError> {
+        let (value,): (i64,) = sqlx::query_as(
+            "UPDATE urls_id_seq SET value = value + 1 WHERE name = 'urls' RETURNING value",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(value as u64)
+    }
+
+    async fn due_for_check(
+        &self,
+        before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<UrlRecord>, ShortenError> {
+        let records = sqlx::query_as(
+            "SELECT id, url FROM urls WHERE checked_at IS NULL OR checked_at < $1 ORDER BY checked_at, id LIMIT $2",
+        )
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records)
+    }
+
+    async fn record_check(
+        &self,
+        id: &str,
+        status: i32,
+        at: DateTime<Utc>,
+    ) -> Result<(), ShortenError> {
+        sqlx::query("UPDATE urls SET check_status=$2, checked_at=$3 WHERE id=$1")
+            .bind(id)
+            .bind(status)
+            .bind(at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<UrlRecord>, ShortenError> {
+        let records = sqlx::query_as(
+            "SELECT id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at FROM urls WHERE owner=$1 ORDER BY id",
+        )
+        .bind(owner)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(records)
+    }
+
+    async fn update(
+        &self,
+        id: &str,
+        owner: &str,
+        update: &LinkUpdate,
+    ) -> Result<Option<UrlRecord>, ShortenError> {
+        let result = sqlx::query_as(
+            r#"
+            UPDATE urls SET url=COALESCE($3, url), preview=COALESCE($4, preview),
+                query_merge=COALESCE($5, query_merge), redirect_type=COALESCE($6, redirect_type),
+                -- 目标变了就需要重新检查
+                check_status=CASE WHEN $3 IS NULL THEN check_status END,
+                checked_at=CASE WHEN $3 IS NULL THEN checked_at END
+            WHERE id=$1 AND owner=$2
+            RETURNING id, url, owner, preview, query_merge, redirect_type, clicks, created_at, check_status, checked_at
+            "#,
+        )
+        .bind(id)
+        .bind(owner)
+        .bind(&update.url)
+        .bind(update.preview)
+        .bind(update.query_merge.map(|q| q.to_string()))
+        .bind(update.redirect_type.map(|r| u16::from(r) as i32))
+        .fetch_optional(&self.pool)
+        .await;
+
+        match result {
+            Ok(record) => Ok(record),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(
+                ShortenError::UrlExists(update.url.clone().unwrap_or_default()),
+            ),
+            Err(e) => Err(ShortenError::DatabaseError(e)),
+        }
+    }
+
+    async fn delete(&self, id: &str, owner: &str) -> Result<bool, ShortenError> {
+        let result = sqlx::query("DELETE FROM urls WHERE id=$1 AND owner=$2")
+            .bind(id)
+            .bind(owner)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn migrate_refuses_newer_schema() -> anyhow::Result<()> {
+        let store = SqliteStore::try_new("sqlite::memory:", 1).await?;
+        let version = store.migrate().await?;
+
+        // 模拟更新版本的服务已经迁移过这个库
+        sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES ($1, 'from the future', TRUE, x'00', 0)")
+            .bind(version + 1)
+            .execute(&store.pool)
+            .await?;
+        match store.migrate().await {
+            Err(ShortenError::SchemaTooNew(db, known)) => {
+                assert_eq!(db, version + 1);
+                assert_eq!(known, version);
+            }
+            other => panic!("expected SchemaTooNew, got {other:?}"),
+        }
+        Ok(())
+    }
+}
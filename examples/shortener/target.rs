@@ -0,0 +1,245 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use url::{form_urlencoded, Url};
+use utoipa::{
+    openapi::{ObjectBuilder, RefOr, Schema, SchemaType},
+    ToSchema,
+};
+
+use crate::error::ShortenError;
+
+/// Placeholder of a templated target, replaced by the path after the short id,
+/// e.g. `shop.com/{path}` makes `/:id/shoes` go to `https://shop.com/shoes`.
+pub const PATH_PLACEHOLDER: &str = "{path}";
+
+/// Query parameters consumed by the shortener itself and never forwarded.
+const RESERVED_PARAMS: [&str; 1] = ["preview"];
+
+/// What happens to the query string of a visit, e.g. `?utm_source=x`.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, EnumString, Display, Serialize, Deserialize, ToSchema,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum QueryMerge {
+    /// Drop the incoming parameters.
+    #[default]
+    Ignore,
+    /// Add the incoming parameters after the ones of the target.
+    Append,
+    /// Like `append`, but an incoming parameter replaces a target parameter of the same name.
+    Merge,
+}
+
+impl TryFrom<String> for QueryMerge {
+    type Error = strum::ParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Status code of the redirect, 301/308 are permanent and cached by browsers,
+/// 302/303/307 are temporary.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum RedirectType {
+    MovedPermanently,
+    Found,
+    #[default]
+    SeeOther,
+    TemporaryRedirect,
+    PermanentRedirect,
+}
+
+impl RedirectType {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RedirectType::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            RedirectType::Found => StatusCode::FOUND,
+            RedirectType::SeeOther => StatusCode::SEE_OTHER,
+            RedirectType::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            RedirectType::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    /// Redirect to `target` with this status code.
+    pub fn to(&self, target: &str) -> Response {
+        (self.status(), [(header::LOCATION, target)]).into_response()
+    }
+}
+
+// 序列化为数字，derive 生成的是字符串枚举
+impl<'s> ToSchema<'s> for RedirectType {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let schema = ObjectBuilder::new()
+            .schema_type(SchemaType::Integer)
+            .description(Some("Status code of the redirect"))
+            .enum_values(Some([301, 302, 303, 307, 308]))
+            .default(Some(303.into()));
+        ("RedirectType", schema.into())
+    }
+}
+
+impl TryFrom<u16> for RedirectType {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 => Ok(RedirectType::MovedPermanently),
+            302 => Ok(RedirectType::Found),
+            303 => Ok(RedirectType::SeeOther),
+            307 => Ok(RedirectType::TemporaryRedirect),
+            308 => Ok(RedirectType::PermanentRedirect),
+            _ => Err(format!(
+                "redirect_type {code} must be 301, 302, 303, 307 or 308"
+            )),
+        }
+    }
+}
+
+// 数据库里存为 INTEGER
+impl TryFrom<i32> for RedirectType {
+    type Error = String;
+
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        u16::try_from(code).map_err(|e| e.to_string())?.try_into()
+    }
+}
+
+impl From<RedirectType> for u16 {
+    fn from(redirect_type: RedirectType) -> Self {
+        redirect_type.status().as_u16()
+    }
+}
+
+/// Build the destination of a visit: `url` is the stored target without scheme,
+/// `path` the part after the short id and `query` the incoming query string.
+pub fn resolve(
+    url: &str,
+    path: Option<&str>,
+    query: Option<&str>,
+    policy: QueryMerge,
+) -> Result<String, ShortenError> {
+    let target = match (url.contains(PATH_PLACEHOLDER), path) {
+        (true, path) => {
+            let path = path.unwrap_or_default();
+            let target = format!("https://{}", url.replace(PATH_PLACEHOLDER, path));
+            let base = Url::parse(&format!("https://{}", url.replace(PATH_PLACEHOLDER, "")))?;
+            // 路径不能改变目标的域名，例如 shop.com{path} 配上 @evil.com
+            let parsed = Url::parse(&target)?;
+            if parsed.host() != base.host() || parsed.port() != base.port() {
+                return Err(ShortenError::RouteNotFound(path.to_string()));
+            }
+            target
+        }
+        // 只有模板链接才接受 id 后面的路径
+        (false, Some(path)) => return Err(ShortenError::RouteNotFound(path.to_string())),
+        (false, None) => format!("https://{url}"),
+    };
+
+    let incoming: Vec<(String, String)> =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(k, _)| !RESERVED_PARAMS.contains(&k.as_ref()))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+    // 不需要改写时原样返回
+    if incoming.is_empty() || policy == QueryMerge::Ignore {
+        return Ok(target);
+    }
+
+    let mut target = Url::parse(&target)?;
+    let kept: Vec<(String, String)> = target
+        .query_pairs()
+        .filter(|(k, _)| {
+            policy == QueryMerge::Append || !incoming.iter().any(|(name, _)| name == k)
+        })
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    target
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .extend_pairs(incoming);
+    Ok(target.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_merge_policies() -> anyhow::Result<()> {
+        let url = "shop.com/sale?utm_source=mail&page=1";
+        let query = Some("utm_source=x&utm_medium=y&preview=0");
+
+        assert_eq!(
+            resolve(url, None, query, QueryMerge::Ignore)?,
+            "https://shop.com/sale?utm_source=mail&page=1"
+        );
+        assert_eq!(
+            resolve(url, None, query, QueryMerge::Append)?,
+            "https://shop.com/sale?utm_source=mail&page=1&utm_source=x&utm_medium=y"
+        );
+        assert_eq!(
+            resolve(url, None, query, QueryMerge::Merge)?,
+            "https://shop.com/sale?page=1&utm_source=x&utm_medium=y"
+        );
+        // 没有新参数时保留原样
+        assert_eq!(
+            resolve(
+                "shop.com/a%20b?q=a%20b",
+                None,
+                Some("preview=1"),
+                QueryMerge::Merge
+            )?,
+            "https://shop.com/a%20b?q=a%20b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_types() {
+        for code in [301, 302, 303, 307, 308] {
+            let redirect_type = RedirectType::try_from(code).unwrap();
+            assert_eq!(u16::from(redirect_type), code);
+            let res = redirect_type.to("https://www.rust-lang.org");
+            assert_eq!(res.status().as_u16(), code);
+            assert_eq!(res.headers()[header::LOCATION], "https://www.rust-lang.org");
+        }
+        assert!(RedirectType::try_from(200u16).is_err());
+        assert!(RedirectType::try_from(-1i32).is_err());
+        assert_eq!(RedirectType::default().status(), StatusCode::SEE_OTHER);
+    }
+
+    #[test]
+    fn templated_targets() -> anyhow::Result<()> {
+        let url = "shop.com/{path}?ref=short";
+        assert_eq!(
+            resolve(url, Some("shoes/red"), None, QueryMerge::Ignore)?,
+            "https://shop.com/shoes/red?ref=short"
+        );
+        assert_eq!(
+            resolve(url, None, Some("utm_source=x"), QueryMerge::Append)?,
+            "https://shop.com/?ref=short&utm_source=x"
+        );
+        assert!(matches!(
+            resolve("shop.com", Some("shoes"), None, QueryMerge::Ignore),
+            Err(ShortenError::RouteNotFound(_))
+        ));
+        assert!(matches!(
+            resolve(
+                "shop.com{path}",
+                Some("@evil.com"),
+                None,
+                QueryMerge::Ignore
+            ),
+            Err(ShortenError::RouteNotFound(_))
+        ));
+        Ok(())
+    }
+}